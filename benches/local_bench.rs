@@ -2,6 +2,7 @@
 // https://github.com/arthurprs/quick-cache/blob/master/benches/benchmarks.rs
 
 use std::mem;
+use std::sync::Arc;
 
 use criterion::{Criterion, criterion_group, criterion_main};
 use rand::prelude::*;
@@ -9,6 +10,11 @@ use rand::prelude::*;
 use rand_distr::Zipf;
 
 use also_cache::sync::AlsoCache;
+use also_cache::{Clock, DefaultWeighter, EvictionPolicy, Lru, Options, S3Fifo};
+
+fn policies() -> [Arc<dyn EvictionPolicy>; 3] {
+    [Arc::new(S3Fifo), Arc::new(Lru), Arc::new(Clock)]
+}
 
 pub fn r_benchmark(c: &mut Criterion) {
     const N_SAMPLES: usize = 1_000;
@@ -45,40 +51,52 @@ pub fn rw_benchmark(c: &mut Criterion) {
             let mut g = c.benchmark_group(format!("Zipf N={} S={}", population, s));
             g.throughput(criterion::Throughput::Elements(N_SAMPLES as u64));
             for capacity_ratio in [0.05, 0.1, 0.15] {
-                let capacity = (population * capacity_ratio) as usize;
-                g.bench_function(format!("qc {}", capacity), |b| {
-                    let mut hits = 0u64;
-                    let mut misses = 0u64;
-                    b.iter_batched_ref(
-                        || {
-                            let mut rng = SmallRng::seed_from_u64(1);
-                            let dist = Zipf::new(population, s).unwrap();
-                            let cache = AlsoCache::default(capacity * mem::size_of::<usize>());
-                            for _ in 0..population as usize * 3 {
-                                let sample = dist.sample(&mut rng) as usize;
-                                let _ = cache.insert(sample, &sample);
-                            }
-                            (rng, dist, cache)
-                        },
-                        |(rng, dist, cache)| {
-                            for _ in 0..N_SAMPLES {
-                                let sample = dist.sample(rng) as usize;
-                                if cache.get::<usize>(&sample).is_ok() {
-                                    hits += 1;
-                                } else {
+                for policy in policies() {
+                    let capacity = (population * capacity_ratio) as usize;
+                    g.bench_function(format!("{} {}", policy.name(), capacity), |b| {
+                        let mut hits = 0u64;
+                        let mut misses = 0u64;
+                        b.iter_batched_ref(
+                            || {
+                                let mut rng = SmallRng::seed_from_u64(1);
+                                let dist = Zipf::new(population, s).unwrap();
+                                let options = Options {
+                                    eviction_policy: Arc::clone(&policy),
+                                    ..Options::default()
+                                };
+                                let cache = AlsoCache::with_options(
+                                    0,
+                                    capacity * mem::size_of::<usize>(),
+                                    DefaultWeighter,
+                                    ahash::RandomState::new(),
+                                    options,
+                                );
+                                for _ in 0..population as usize * 3 {
+                                    let sample = dist.sample(&mut rng) as usize;
                                     let _ = cache.insert(sample, &sample);
-                                    misses += 1;
                                 }
-                            }
-                            (hits, misses)
-                        },
-                        criterion::BatchSize::LargeInput,
-                    );
-                    print_times += 1;
-                    if print_times % 10 == 0 {
-                        eprintln!("Hit rate {:?}", hits as f64 / (hits + misses) as f64);
-                    }
-                });
+                                (rng, dist, cache)
+                            },
+                            |(rng, dist, cache)| {
+                                for _ in 0..N_SAMPLES {
+                                    let sample = dist.sample(rng) as usize;
+                                    if cache.get::<usize>(&sample).is_ok() {
+                                        hits += 1;
+                                    } else {
+                                        let _ = cache.insert(sample, &sample);
+                                        misses += 1;
+                                    }
+                                }
+                                (hits, misses)
+                            },
+                            criterion::BatchSize::LargeInput,
+                        );
+                        print_times += 1;
+                        if print_times % 10 == 0 {
+                            eprintln!("Hit rate {:?}", hits as f64 / (hits + misses) as f64);
+                        }
+                    });
+                }
             }
         }
     }
@@ -19,6 +19,15 @@ fn main() {
             also_cache::InsertCacheError::Decode(err) => {
                 println!("Failed to decode value for key '{}': {:?}", key, err)
             }
+            also_cache::InsertCacheError::ShardReset(err) => {
+                println!("Shard was reset while inserting key '{}': {:?}", key, err)
+            }
+            also_cache::InsertCacheError::TooLarge => {
+                println!("Value for key '{}' is too large to be cached", key)
+            }
+            also_cache::InsertCacheError::VersionMismatch => {
+                println!("Key '{}' was changed concurrently", key)
+            }
         },
     }
 
@@ -37,6 +46,12 @@ fn main() {
             also_cache::GetCacheError::KeyNotFound => {
                 println!("Key '{}' not found in cache", key)
             }
+            also_cache::GetCacheError::Negative => {
+                println!("Key '{}' is cached as missing", key)
+            }
+            also_cache::GetCacheError::ShardReset(err) => {
+                println!("Shard was reset while reading key '{}': {:?}", key, err)
+            }
         },
     }
 
@@ -0,0 +1,552 @@
+// Replays an access trace through the cache at given capacities and reports hit ratios.
+// Every request is a `get`, and a miss is followed by an `insert` of the requested size,
+// which is how the cache is used in front of a slower store.
+//
+// Usage: simulate [--format lines|arc|csv|recorded] [--engine cache|shard]
+//                 [--policy s3fifo|lru|clock] [--tinylfu] [--max-frequency N]
+//                 --capacity N[,N...] <TRACE>
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufRead, BufReader};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use bincode::config::standard;
+
+use also_cache::cache_shard::CacheShard;
+use also_cache::namespace::{DEFAULT_NAMESPACE, NsKey};
+use also_cache::sync::{AlsoCache, Weighter};
+use also_cache::trace::{TraceOp, read_trace as read_trace_file};
+use also_cache::{Clock, EvictionPolicy, Lru, Options, S3Fifo};
+
+const USAGE: &str = "\
+Usage: simulate [OPTIONS] --capacity N[,N...] <TRACE>
+
+Replays TRACE (a file, or - for stdin) through the cache once per capacity.
+
+Options:
+  --format lines|arc|csv|recorded
+                          Trace format (default: lines)
+                            lines     one key per line (first column), e.g. LIRS traces
+                            arc       `start count ...` per line, requests blocks start..start+count
+                            csv       `key,size` per line, an optional header is skipped
+                            recorded  trace written by `AlsoCache::start_recording`, its gets
+                                      are replayed
+  --engine cache|shard    Replay through the sharded `AlsoCache` or a single `CacheShard`
+                          (default: cache)
+  --policy s3fifo|lru|clock
+                          Eviction policy (default: s3fifo)
+  --tinylfu               Enable TinyLFU admission to the main queue
+  --max-frequency N       Cap of entry frequencies (default: 3)
+  --capacity N[,N...]     Cache capacities, in bytes for csv and recorded traces and
+                          in entries otherwise.
+                          Can be repeated
+  -h, --help              Print this message";
+
+#[derive(Debug, Clone, Copy)]
+enum TraceFormat {
+    Lines,
+    Arc,
+    Csv,
+    Recorded,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Engine {
+    Cache,
+    Shard,
+}
+
+struct Args {
+    format: TraceFormat,
+    engine: Engine,
+    policy: Arc<dyn EvictionPolicy>,
+    tinylfu_admission: bool,
+    max_frequency: u8,
+    capacities: Vec<u64>,
+    trace: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Access {
+    key: u64,
+    size: u64,
+}
+
+// Values are the sizes of the requests, so entries weigh as much as the requested objects
+// without allocating them.
+#[derive(Debug, Clone, Default)]
+struct SizeWeighter;
+
+impl Weighter<u64> for SizeWeighter {
+    fn weight(&self, _key: &u64, val: &Vec<u8>) -> u64 {
+        bincode::serde::decode_from_slice::<u64, _>(val, standard())
+            .map(|(size, _)| size)
+            .unwrap_or(val.len() as u64)
+    }
+}
+
+enum Simulated {
+    Cache(AlsoCache<u64, SizeWeighter, ahash::RandomState>),
+    Shard(CacheShard<NsKey<u64>, ahash::RandomState>),
+}
+
+impl Simulated {
+    fn new(args: &Args, capacity: u64) -> Self {
+        // fixed seeds, so keys land in the same shards on every run
+        let hasher = ahash::RandomState::with_seeds(1, 2, 3, 4);
+        let ratios = args.policy.queue_ratios();
+        let options = Options {
+            eviction_policy: Arc::clone(&args.policy),
+            tinylfu_admission: args.tinylfu_admission,
+            max_frequency: args.max_frequency,
+            ..Options::default()
+        };
+        match args.engine {
+            Engine::Cache => Simulated::Cache(AlsoCache::with_options(
+                0,
+                capacity as usize,
+                SizeWeighter,
+                hasher,
+                options,
+            )),
+            Engine::Shard => Simulated::Shard(CacheShard::with_options(
+                0,
+                ((capacity as f64 * ratios.small) as u64).max(1),
+                ((capacity as f64 * ratios.main) as u64).max(1),
+                ((capacity as f64 * ratios.ghost) as u64).max(1),
+                options,
+                hasher,
+            )),
+        }
+    }
+
+    // Returns whether the request was a hit
+    fn access(&mut self, access: Access) -> bool {
+        match self {
+            Simulated::Cache(cache) => {
+                if cache.get::<u64>(&access.key).is_ok() {
+                    return true;
+                }
+                // too large entries are simply not cached
+                let _ = cache.insert(access.key, &access.size);
+                false
+            }
+            Simulated::Shard(shard) => {
+                let key = NsKey {
+                    namespace: DEFAULT_NAMESPACE,
+                    generation: 0,
+                    key: access.key,
+                };
+                if shard.get_bytes(&key).is_some() {
+                    return true;
+                }
+                // data can't be empty, nodes without data are not entries
+                let _ = shard.insert_bytes(key, access.size, vec![0]);
+                false
+            }
+        }
+    }
+
+    fn evictions(&self) -> u64 {
+        match self {
+            Simulated::Cache(cache) => cache.get_eviction_count(),
+            Simulated::Shard(shard) => shard.get_evictions(),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("error: {err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let trace = match read_trace(&args.trace, args.format) {
+        Ok(trace) => trace,
+        Err(err) => {
+            eprintln!("error: {}: {err}", args.trace);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let unique_keys = trace
+        .iter()
+        .map(|access| access.key)
+        .collect::<HashSet<_>>()
+        .len();
+    let total_bytes: u64 = trace.iter().map(|access| access.size).sum();
+    println!(
+        "trace {}: {} requests, {} unique keys, {} bytes requested, {} eviction{}",
+        args.trace,
+        trace.len(),
+        unique_keys,
+        total_bytes,
+        args.policy.name(),
+        if args.tinylfu_admission {
+            " with tinylfu admission"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "{:>14} {:>10} {:>15} {:>12}",
+        "capacity", "hit ratio", "byte hit ratio", "evictions"
+    );
+    for &capacity in &args.capacities {
+        let mut cache = Simulated::new(&args, capacity);
+        let mut hits = 0u64;
+        let mut hit_bytes = 0u64;
+        for &access in &trace {
+            if cache.access(access) {
+                hits += 1;
+                hit_bytes += access.size;
+            }
+        }
+        println!(
+            "{:>14} {:>10.4} {:>15.4} {:>12}",
+            capacity,
+            ratio(hits, trace.len() as u64),
+            ratio(hit_bytes, total_bytes),
+            cache.evictions()
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+fn ratio(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+// Returns None if help was requested
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut format = TraceFormat::Lines;
+    let mut engine = Engine::Cache;
+    let mut policy: Arc<dyn EvictionPolicy> = Arc::new(S3Fifo);
+    let mut tinylfu_admission = false;
+    let mut max_frequency = Options::default().max_frequency;
+    let mut capacities = Vec::new();
+    let mut trace = None;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} requires a value"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--tinylfu" => tinylfu_admission = true,
+            "--format" => {
+                format = match value("--format")?.as_str() {
+                    "lines" => TraceFormat::Lines,
+                    "arc" => TraceFormat::Arc,
+                    "csv" => TraceFormat::Csv,
+                    "recorded" => TraceFormat::Recorded,
+                    other => return Err(format!("unknown trace format {other:?}")),
+                }
+            }
+            "--engine" => {
+                engine = match value("--engine")?.as_str() {
+                    "cache" => Engine::Cache,
+                    "shard" => Engine::Shard,
+                    other => return Err(format!("unknown engine {other:?}")),
+                }
+            }
+            "--policy" => {
+                policy = match value("--policy")?.as_str() {
+                    "s3fifo" => Arc::new(S3Fifo),
+                    "lru" => Arc::new(Lru),
+                    "clock" => Arc::new(Clock),
+                    other => return Err(format!("unknown eviction policy {other:?}")),
+                }
+            }
+            "--max-frequency" => {
+                let max = value("--max-frequency")?;
+                max_frequency = max
+                    .parse()
+                    .map_err(|_| format!("invalid maximum frequency {max:?}"))?;
+            }
+            "--capacity" => {
+                for capacity in value("--capacity")?.split(',') {
+                    let capacity = capacity
+                        .trim()
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|&capacity| capacity > 0)
+                        .ok_or(format!("invalid capacity {capacity:?}"))?;
+                    capacities.push(capacity);
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ if trace.is_none() => trace = Some(arg),
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+    if capacities.is_empty() {
+        return Err("--capacity is required".to_string());
+    }
+    let trace = trace.ok_or("trace file is required")?;
+    Ok(Some(Args {
+        format,
+        engine,
+        policy,
+        tinylfu_admission,
+        max_frequency,
+        capacities,
+        trace,
+    }))
+}
+
+fn read_trace(path: &str, format: TraceFormat) -> io::Result<Vec<Access>> {
+    if let TraceFormat::Recorded = format {
+        return read_recorded_trace(path);
+    }
+    if path == "-" {
+        parse_trace(io::stdin().lock(), format)
+    } else {
+        parse_trace(BufReader::new(File::open(path)?), format)
+    }
+}
+
+// Parses a text trace
+fn parse_trace(reader: impl BufRead, format: TraceFormat) -> io::Result<Vec<Access>> {
+    let mut trace = Vec::new();
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        // LIRS traces mark the end of a trace with `*`
+        if line.is_empty() || line.starts_with('#') || line == "*" {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: invalid {format:?} record {line:?}", line_idx + 1),
+            )
+        };
+        match format {
+            TraceFormat::Lines => {
+                let key = line.split_whitespace().next().ok_or_else(invalid)?;
+                trace.push(Access {
+                    key: parse_key(key),
+                    size: 1,
+                });
+            }
+            TraceFormat::Arc => {
+                let mut fields = line.split_whitespace().map(str::parse::<u64>);
+                let (Some(Ok(start)), Some(Ok(count))) = (fields.next(), fields.next()) else {
+                    return Err(invalid());
+                };
+                trace.extend(
+                    (start..start.saturating_add(count)).map(|key| Access { key, size: 1 }),
+                );
+            }
+            TraceFormat::Csv => {
+                let mut fields = line.split(',').map(str::trim);
+                let (Some(key), Some(size)) = (fields.next(), fields.next()) else {
+                    return Err(invalid());
+                };
+                match size.parse::<u64>() {
+                    Ok(size) => trace.push(Access {
+                        key: parse_key(key),
+                        size,
+                    }),
+                    // header
+                    Err(_) if trace.is_empty() => {}
+                    Err(_) => return Err(invalid()),
+                }
+            }
+            TraceFormat::Recorded => unreachable!("recorded traces are binary"),
+        }
+    }
+    Ok(trace)
+}
+
+// Gets of a recorded trace. Misses don't have a size, so the size of a key is the first one
+// seen in any of its records.
+fn read_recorded_trace(path: &str) -> io::Result<Vec<Access>> {
+    let trace = read_trace_file(path)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err:?}")))?;
+    let mut sizes = HashMap::new();
+    for record in &trace.records {
+        if record.size > 0 {
+            sizes.entry(record.key_hash).or_insert(record.size as u64);
+        }
+    }
+    Ok(trace
+        .records
+        .iter()
+        .filter(|record| matches!(record.op, TraceOp::GetHit | TraceOp::GetMiss))
+        .map(|record| Access {
+            key: record.key_hash,
+            size: sizes.get(&record.key_hash).copied().unwrap_or(1),
+        })
+        .collect())
+}
+
+// Numeric keys are used as is, others are hashed
+fn parse_key(key: &str) -> u64 {
+    key.parse().unwrap_or_else(|_| {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Option<Args>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse(trace: &str, format: TraceFormat) -> io::Result<Vec<(u64, u64)>> {
+        parse_trace(trace.as_bytes(), format).map(|trace| {
+            trace
+                .iter()
+                .map(|access| (access.key, access.size))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args(&["--capacity", "10, 20", "trace.txt", "--capacity", "30"])
+            .expect("args should be valid")
+            .expect("help was not requested");
+        assert!(matches!(parsed.format, TraceFormat::Lines));
+        assert!(matches!(parsed.engine, Engine::Cache));
+        assert_eq!(parsed.policy.name(), S3Fifo.name());
+        assert!(!parsed.tinylfu_admission);
+        assert_eq!(parsed.max_frequency, Options::default().max_frequency);
+        assert_eq!(parsed.capacities, vec![10, 20, 30]);
+        assert_eq!(parsed.trace, "trace.txt");
+
+        let parsed = args(&[
+            "--format",
+            "csv",
+            "--engine",
+            "shard",
+            "--policy",
+            "lru",
+            "--tinylfu",
+            "--max-frequency",
+            "7",
+            "--capacity",
+            "100",
+            "-",
+        ])
+        .expect("args should be valid")
+        .expect("help was not requested");
+        assert!(matches!(parsed.format, TraceFormat::Csv));
+        assert!(matches!(parsed.engine, Engine::Shard));
+        assert_eq!(parsed.policy.name(), Lru.name());
+        assert!(parsed.tinylfu_admission);
+        assert_eq!(parsed.max_frequency, 7);
+        assert_eq!(parsed.trace, "-");
+
+        assert!(matches!(args(&["--capacity", "1", "--help"]), Ok(None)));
+        for invalid in [
+            &["trace.txt"][..],
+            &["--capacity", "10"],
+            &["--capacity", "0", "trace.txt"],
+            &["--capacity", "ten", "trace.txt"],
+            &["--capacity"],
+            &["--format", "json", "--capacity", "10", "trace.txt"],
+            &["--engine", "disk", "--capacity", "10", "trace.txt"],
+            &["--policy", "arc", "--capacity", "10", "trace.txt"],
+            &["--max-frequency", "300", "--capacity", "10", "trace.txt"],
+            &["--verbose", "--capacity", "10", "trace.txt"],
+            &["--capacity", "10", "trace.txt", "other.txt"],
+        ] {
+            assert!(args(invalid).is_err(), "{invalid:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_lines_trace() {
+        let trace = "# comment\n1\n\n  2 extra columns\nkey\n1\n*\n";
+        let parsed = parse(trace, TraceFormat::Lines).expect("trace should be valid");
+        assert_eq!(parsed, vec![(1, 1), (2, 1), (parse_key("key"), 1), (1, 1)]);
+        assert!(
+            parse("", TraceFormat::Lines)
+                .expect("empty trace is valid")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_parse_arc_trace() {
+        let trace = "10 3 0 1\n5 1\n7 0\n";
+        let parsed = parse(trace, TraceFormat::Arc).expect("trace should be valid");
+        assert_eq!(parsed, vec![(10, 1), (11, 1), (12, 1), (5, 1)]);
+        // start near the end of the key space, the range is cut there
+        let parsed =
+            parse(&format!("{} 5", u64::MAX - 1), TraceFormat::Arc).expect("trace should be valid");
+        assert_eq!(parsed, vec![(u64::MAX - 1, 1)]);
+
+        for invalid in ["10\n", "a 3\n", "10 -1\n"] {
+            let err = parse(invalid, TraceFormat::Arc).expect_err("record should be rejected");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        let err = parse("1 1\n2 2\nbad\n", TraceFormat::Arc).expect_err("should be rejected");
+        assert!(err.to_string().starts_with("line 3:"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_csv_trace() {
+        let trace = "key,size\n1,100\n a , 20 \n1,100,extra\n";
+        let parsed = parse(trace, TraceFormat::Csv).expect("trace should be valid");
+        assert_eq!(parsed, vec![(1, 100), (parse_key("a"), 20), (1, 100)]);
+
+        // only the first record can be a header
+        let err = parse("1,100\nkey,size\n", TraceFormat::Csv).expect_err("should be rejected");
+        assert!(err.to_string().starts_with("line 2:"), "{err}");
+        let err = parse("1\n", TraceFormat::Csv).expect_err("should be rejected");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_read_recorded_trace() {
+        use also_cache::trace::TraceOptions;
+
+        let path =
+            std::env::temp_dir().join(format!("simulate_recorded_{}.bin", std::process::id()));
+        let cache = AlsoCache::default(2000);
+        cache
+            .start_recording(TraceOptions {
+                sample_one_in: 1,
+                ..TraceOptions::new(&path)
+            })
+            .expect("recording should start");
+        let _ = cache.get::<String>(&1);
+        cache.insert(1, &"value").expect("insert should succeed");
+        cache.get::<String>(&1).expect("get should succeed");
+        assert!(cache.delete(&1));
+        cache.stop_recording().expect("trace should be flushed");
+
+        let path_str = path.to_str().expect("path should be valid UTF-8");
+        let trace = read_trace(path_str, TraceFormat::Recorded).expect("trace should be valid");
+        // only gets are replayed, the miss gets the size seen in later records
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].key, trace[1].key);
+        let value_size = bincode::serde::encode_to_vec("value", standard())
+            .expect("value should be encoded")
+            .len() as u64;
+        assert_eq!((trace[0].size, trace[1].size), (value_size, value_size));
+
+        std::fs::write(&path, b"not a trace").expect("file should be writable");
+        let err = read_trace(path_str, TraceFormat::Recorded).expect_err("should be rejected");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).expect("trace file should be removed");
+    }
+}
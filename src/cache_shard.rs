@@ -1,14 +1,29 @@
+use std::collections::HashMap;
 use std::hash::BuildHasher;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{hash::Hash, marker::PhantomData};
 
-use hashbrown::HashTable;
+use hashbrown::{Equivalent, HashTable};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+use crate::ghost::{GhostQueue, fingerprint};
+use crate::hot_keys::HeavyHitters;
+use crate::namespace::{NamespaceId, NamespaceQuota, Namespaced};
+use crate::options::Options;
+use crate::sketch::FrequencySketch;
+use crate::tag_index::TagIndex;
+
+// How many nodes from the head of the queue are considered when looking for the
+// cheapest to recompute entry to evict (see `pop_victim`)
+const COST_WINDOW: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum QueueTypeId {
     NoQueue,
     Small,
     Main,
     Ghost,
+    Pinned,
 }
 
 // Cache entry, stores the actual data as bytes on the heap
@@ -22,8 +37,85 @@ struct Node {
     next: u32,
     prev: u32,
 
+    // recomputation cost set by the user, among nodes with freq == 0 cheaper ones are evicted first
+    cost: u32,
+
+    // milliseconds since UNIX epoch after which the node is treated as missing, 0 means never
+    expires_at: u64,
+
+    // changes on every update of the entry, used for compare-and-set
+    version: u64,
+
     freq: u8,
     queue: QueueTypeId,
+
+    // marks a cached miss: node has no data, but it is still a valid entry
+    negative: bool,
+}
+
+// Everything that describes an entry being inserted, besides its key
+struct NewEntry {
+    data: Vec<u8>,
+    weight: u64,
+    cost: u32,
+    expires_at: u64,
+    negative: bool,
+    pinned: bool,
+    tags: Vec<String>,
+}
+
+impl NewEntry {
+    fn new(data: Vec<u8>, weight: u64) -> Self {
+        NewEntry {
+            data,
+            weight,
+            cost: 0,
+            expires_at: 0,
+            negative: false,
+            pinned: false,
+            tags: Vec::new(),
+        }
+    }
+}
+
+// Quota of a namespace in a shard, and the weight of its entries in small, main and pinned queues
+#[derive(Debug)]
+struct ShardQuota {
+    quota: NamespaceQuota,
+    weight: u64,
+    // part of `weight` in the pinned queue, it can't be evicted
+    pinned: u64,
+}
+
+impl ShardQuota {
+    // Evictable weight is over the maximum: pinned entries alone never make a namespace evict
+    fn is_over(&self) -> bool {
+        self.quota
+            .max_weight
+            .is_some_and(|max_weight| self.weight - self.pinned > max_weight)
+    }
+}
+
+/// Copy of a shard entry, used to save and restore snapshots of the cache
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeSnapshot<Key> {
+    pub key: Key,
+    pub data: Vec<u8>,
+    pub weight: u64,
+    pub cost: u32,
+    pub expires_at: u64,
+    pub freq: u8,
+    pub queue: QueueTypeId,
+    pub negative: bool,
+    pub tags: Vec<String>,
+}
+
+/// Entry found in a shard
+#[derive(Debug)]
+pub enum ShardEntry<'a> {
+    Data(&'a Vec<u8>),
+    /// Cached miss, inserted with `insert_absent`
+    Absent,
 }
 
 // This represents a reference to a node in a CacheShard. Nodes can be in different states:
@@ -33,7 +125,7 @@ struct Node {
 // So for example, if a function takes `ref: NodeRef<NoQueue, Occupied>`, it means this function
 // assumes that node in `nodes[ref.idx]` is not part of any queue and is occupied (not freed).
 //
-// Q: Queue type - SmallQueue, MainQueue, GhostQueue, or NoQueue if not in any queue
+// Q: Queue type - SmallQueue, MainQueue, PinnedQueue, or NoQueue if not in any queue
 // H: Node state - Occupied (has data) or Free (available for reuse)
 #[derive(Debug)]
 pub struct NodeRef<Q, H> {
@@ -48,7 +140,7 @@ struct SmallQueue;
 #[derive(Debug, Clone, Copy)]
 struct MainQueue;
 #[derive(Debug, Clone, Copy)]
-struct GhostQueue;
+struct PinnedQueue;
 #[derive(Debug, Clone, Copy)]
 struct NoQueue;
 #[derive(Debug, Clone, Copy)]
@@ -65,8 +157,8 @@ impl QueueWithMembers for SmallQueue {
 impl QueueWithMembers for MainQueue {
     const QUEUE_ID: QueueTypeId = QueueTypeId::Main;
 }
-impl QueueWithMembers for GhostQueue {
-    const QUEUE_ID: QueueTypeId = QueueTypeId::Ghost;
+impl QueueWithMembers for PinnedQueue {
+    const QUEUE_ID: QueueTypeId = QueueTypeId::Pinned;
 }
 
 #[derive(Debug)]
@@ -75,6 +167,125 @@ enum QueueHead<Q> {
     None,
 }
 
+impl<Q> QueueHead<Q> {
+    fn idx(&self) -> Option<u32> {
+        match self {
+            QueueHead::Some(head) => Some(head.idx),
+            QueueHead::None => None,
+        }
+    }
+}
+
+/// Inconsistent shard state detected at runtime. The shard resets itself (drops all entries)
+/// before reporting it, so it is always usable again afterwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShardError {
+    /// Size counter of a queue was over its threshold, but the queue itself was empty.
+    EmptyQueue(QueueTypeId),
+    /// A thread panicked while holding the shard lock.
+    Poisoned,
+}
+
+/// Inconsistency found by `CacheShard::validate`. Nodes are identified by their index.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum ShardProblem {
+    /// `prev` of the node after `idx` in the queue doesn't point back to `idx`
+    BrokenLink { queue: QueueTypeId, idx: u32 },
+    /// Node reached by walking the queue is marked as a member of another queue
+    WrongQueue {
+        queue: QueueTypeId,
+        idx: u32,
+        found: QueueTypeId,
+    },
+    /// Walking the queue reached a node twice before getting back to the head,
+    /// or reached a node that is out of bounds
+    Unterminated { queue: QueueTypeId, idx: u32 },
+    /// Size counter of the queue differs from the total weight of its nodes
+    SizeMismatch {
+        queue: QueueTypeId,
+        counted: u64,
+        recomputed: u64,
+    },
+    /// Weight of a namespace with a quota differs from the total weight of its nodes
+    QuotaMismatch {
+        namespace: NamespaceId,
+        counted: u64,
+        recomputed: u64,
+    },
+    /// Map entry points to a node that is free, out of bounds or not in any queue
+    DanglingMapEntry { idx: u32 },
+    /// Map entry is not where the hash of its key says (the key can't be found)
+    MisplacedMapEntry { idx: u32 },
+    /// Node of a queue has no map entry
+    UnmappedNode { idx: u32 },
+    /// Node of the freelist is reachable from a queue, or is in the freelist twice
+    ReachableFreeNode { idx: u32 },
+    /// Node is neither in a queue nor in the freelist
+    LeakedNode { idx: u32 },
+}
+
+/// Result of `CacheShard::validate`: sizes recomputed by walking the queues, and the
+/// problems found along the way.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ShardReport {
+    pub small_entries: usize,
+    pub main_entries: usize,
+    pub pinned_entries: usize,
+    pub ghosts: usize,
+    pub free_nodes: usize,
+    pub small_size: u64,
+    pub main_size: u64,
+    pub pinned_size: u64,
+    pub ghost_size: u64,
+    pub problems: Vec<ShardProblem>,
+}
+
+impl ShardReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Entry of a queue, see `CacheShard::inspect`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueEntryInfo<Key> {
+    pub key: Key,
+    pub freq: u8,
+    pub weight: u64,
+}
+
+/// Queue of a shard, see `CacheShard::inspect`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueInfo<Key> {
+    /// Number of entries
+    pub len: usize,
+    /// Total weight of the entries
+    pub size: u64,
+    /// Oldest entries (next to be evicted) first
+    pub oldest: Vec<QueueEntryInfo<Key>>,
+}
+
+/// State of a shard returned by `CacheShard::inspect`, meant for diagnostics endpoints
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShardInfo<Key> {
+    pub small: QueueInfo<Key>,
+    pub main: QueueInfo<Key>,
+    pub pinned: QueueInfo<Key>,
+    /// Number and total weight of the ghosts (fingerprints of evicted keys)
+    pub ghosts: usize,
+    pub ghost_size: u64,
+    pub small_threshold: u64,
+    pub main_threshold: u64,
+    pub ghost_threshold: u64,
+    /// Keys in the map: live entries, and expired ones not removed yet
+    pub keys: usize,
+    /// Nodes in the freelist, reused by the next inserts
+    pub free_nodes: usize,
+    /// Allocated capacity of the node vector
+    pub node_capacity: usize,
+    pub evictions: u64,
+}
+
 #[derive(Debug)]
 pub struct CacheShard<Key, B> {
     map: HashTable<u32>,
@@ -88,34 +299,57 @@ pub struct CacheShard<Key, B> {
     // next insert will cause eviction in that queue
     small_size: u64,
     main_size: u64,
-    ghost_size: u64,
     small_threshold: u64,
     main_threshold: u64,
     ghost_threshold: u64,
 
+    // pinned entries are never evicted, small and main queues share what's left of the budget
+    pinned_size: u64,
+
     small_head: QueueHead<SmallQueue>,
     main_head: QueueHead<MainQueue>,
-    ghost_head: QueueHead<GhostQueue>,
+    pinned_head: QueueHead<PinnedQueue>,
+
+    // fingerprints of entries evicted from the small queue, its size is their total weight
+    ghosts: GhostQueue,
+
+    // version assigned to the next inserted or updated entry, never reset so versions
+    // of a key keep growing even if it is deleted and inserted again
+    next_version: u64,
+
+    // namespaces with weight limits, weight is only tracked for them
+    quotas: HashMap<NamespaceId, ShardQuota>,
+
+    tags: TagIndex,
+
+    // entries evicted from small and main queues since the shard was created
+    evictions: u64,
+
+    // eviction policy has no small queue, new entries go straight to the main queue
+    admit_to_main: bool,
+
+    // access frequencies of keys for TinyLFU admission, if it is enabled
+    sketch: Option<FrequencySketch>,
+
+    // milliseconds since UNIX epoch when frequencies were last halved
+    last_decay: u64,
+
+    // most accessed keys, if `hot_keys_tracked` is set
+    hot_keys: Option<HeavyHitters>,
+
+    options: Options,
 }
 
-impl<Key: Eq + Hash, B: BuildHasher> CacheShard<Key, B> {
+impl<Key: Eq + Hash + Namespaced, B: BuildHasher> CacheShard<Key, B> {
     pub fn new(small_threshold: u64, main_threshold: u64, ghost_threshold: u64, hasher: B) -> Self {
-        Self {
-            map: HashTable::new(),
-            nodes_keys: Vec::new(),
-            hasher,
-            nodes: Vec::new(),
-            freelist: Vec::new(),
-            small_size: 0,
-            main_size: 0,
-            ghost_size: 0,
+        Self::with_options(
+            0,
             small_threshold,
             main_threshold,
             ghost_threshold,
-            small_head: QueueHead::None,
-            main_head: QueueHead::None,
-            ghost_head: QueueHead::None,
-        }
+            Options::default(),
+            hasher,
+        )
     }
 
     pub fn with_estimated_count(
@@ -124,6 +358,24 @@ impl<Key: Eq + Hash, B: BuildHasher> CacheShard<Key, B> {
         main_threshold: u64,
         ghost_threshold: u64,
         hasher: B,
+    ) -> Self {
+        Self::with_options(
+            estimated_items_count,
+            small_threshold,
+            main_threshold,
+            ghost_threshold,
+            Options::default(),
+            hasher,
+        )
+    }
+
+    pub fn with_options(
+        estimated_items_count: usize,
+        small_threshold: u64,
+        main_threshold: u64,
+        ghost_threshold: u64,
+        options: Options,
+        hasher: B,
     ) -> Self {
         Self {
             map: HashTable::with_capacity(estimated_items_count),
@@ -133,38 +385,231 @@ impl<Key: Eq + Hash, B: BuildHasher> CacheShard<Key, B> {
             freelist: Vec::with_capacity(estimated_items_count / 4),
             small_size: 0,
             main_size: 0,
-            ghost_size: 0,
             small_threshold,
             main_threshold,
             ghost_threshold,
+            pinned_size: 0,
             small_head: QueueHead::None,
             main_head: QueueHead::None,
-            ghost_head: QueueHead::None,
+            pinned_head: QueueHead::None,
+            ghosts: GhostQueue::default(),
+            next_version: 1,
+            quotas: HashMap::new(),
+            tags: TagIndex::default(),
+            evictions: 0,
+            admit_to_main: options.eviction_policy.queue_ratios().small == 0.0,
+            sketch: options
+                .tinylfu_admission
+                .then(|| FrequencySketch::new(estimated_items_count)),
+            last_decay: now_millis(),
+            hot_keys: (options.hot_keys_tracked > 0)
+                .then(|| HeavyHitters::new(options.hot_keys_tracked)),
+            options,
         }
     }
 
     /// Retrieves a cache entry by key.
     #[inline(always)]
-    pub fn get_bytes(&mut self, key: &Key) -> Option<&Vec<u8>> {
+    pub fn get_bytes<Q: Hash + Equivalent<Key> + ?Sized>(&mut self, key: &Q) -> Option<&Vec<u8>> {
+        match self.get_entry(key)? {
+            ShardEntry::Data(data) => Some(data),
+            ShardEntry::Absent => None,
+        }
+    }
+
+    /// Retrieves a cache entry by key, including cached misses.
+    /// Expired entries are removed on access.
+    #[inline(always)]
+    pub fn get_entry<Q: Hash + Equivalent<Key> + ?Sized>(
+        &mut self,
+        key: &Q,
+    ) -> Option<ShardEntry<'_>> {
+        self.get_entry_with_version(key).map(|(entry, _)| entry)
+    }
+
+    /// Same as `get_entry`, but also returns the current version of the entry.
+    #[inline(always)]
+    pub fn get_entry_with_version<Q: Hash + Equivalent<Key> + ?Sized>(
+        &mut self,
+        key: &Q,
+    ) -> Option<(ShardEntry<'_>, u64)> {
+        self.decay_frequencies_if_due();
         let hash = self.hasher.hash_one(key);
-        let idx = self
-            .map
-            .find(hash, |&idx| self.nodes_keys[idx as usize] == *key)
-            .map(|&idx| idx as usize)?;
-        if self.nodes[idx].freq < 3 {
-            self.nodes[idx].freq += 1;
+        self.record_access(hash);
+        let idx = self.find_idx_hashed(hash, key)?;
+        if self.is_expired(idx) {
+            self.remove(idx);
+            return None;
         }
-        if !self.nodes[idx].data.is_empty() {
-            Some(&self.nodes[idx].data)
+        self.touch(idx);
+        let node = &self.nodes[idx];
+        if !node.data.is_empty() {
+            Some((ShardEntry::Data(&node.data), node.version))
+        } else if node.negative {
+            Some((ShardEntry::Absent, node.version))
         } else {
             None
         }
     }
 
+    /// Returns data of a live entry without counting it as an access.
+    pub fn peek_bytes<Q: Hash + Equivalent<Key> + ?Sized>(&self, key: &Q) -> Option<&Vec<u8>> {
+        let idx = self.find_idx(key)?;
+        let node = &self.nodes[idx];
+        if node.data.is_empty() || self.is_expired(idx) {
+            return None;
+        }
+        Some(&node.data)
+    }
+
     /// Inserts or updates a cache entry by key.
+    /// If the shard turns out to be inconsistent during eviction, it is reset and an error is returned.
     #[inline(always)]
-    pub fn insert_bytes(&mut self, key: Key, data_size: u64, data: Vec<u8>) {
+    pub fn insert_bytes(
+        &mut self,
+        key: Key,
+        data_size: u64,
+        data: Vec<u8>,
+    ) -> Result<(), ShardError> {
+        self.insert_node(key, NewEntry::new(data, data_size))
+            .map(|_| ())
+    }
+
+    /// Inserts or updates a cache entry by key, with a given recomputation cost.
+    /// Among entries with equal frequency, cheaper ones are evicted first.
+    pub fn insert_bytes_with_cost(
+        &mut self,
+        key: Key,
+        data_size: u64,
+        data: Vec<u8>,
+        cost: u32,
+    ) -> Result<(), ShardError> {
+        let entry = NewEntry {
+            cost,
+            ..NewEntry::new(data, data_size)
+        };
+        self.insert_node(key, entry).map(|_| ())
+    }
+
+    /// Inserts or updates a cache entry by key and pins it, so it is never evicted until unpinned.
+    pub fn insert_bytes_pinned(
+        &mut self,
+        key: Key,
+        data_size: u64,
+        data: Vec<u8>,
+    ) -> Result<(), ShardError> {
+        let entry = NewEntry {
+            pinned: true,
+            ..NewEntry::new(data, data_size)
+        };
+        self.insert_node(key, entry).map(|_| ())
+    }
+
+    /// Inserts or updates a cache entry by key, attaching tags to it (see `invalidate_tag`).
+    /// Tags of an updated entry are replaced.
+    pub fn insert_bytes_tagged(
+        &mut self,
+        key: Key,
+        data_size: u64,
+        data: Vec<u8>,
+        tags: Vec<String>,
+    ) -> Result<(), ShardError> {
+        let entry = NewEntry {
+            tags,
+            ..NewEntry::new(data, data_size)
+        };
+        self.insert_node(key, entry).map(|_| ())
+    }
+
+    /// Updates a live entry keeping its expiration time, tags and cost, or inserts a new
+    /// entry that expires after `ttl` (if set). A cached miss is replaced like a missing key.
+    pub fn upsert_bytes(
+        &mut self,
+        key: Key,
+        data_size: u64,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), ShardError> {
+        let entry = match self.find_idx(&key) {
+            Some(idx)
+                if self.is_resident(idx) && !self.nodes[idx].negative && !self.is_expired(idx) =>
+            {
+                self.value_update(idx, data, data_size)
+            }
+            _ => NewEntry {
+                expires_at: ttl
+                    .map_or(0, |ttl| now_millis().saturating_add(ttl.as_millis() as u64)),
+                ..NewEntry::new(data, data_size)
+            },
+        };
+        self.insert_node(key, entry).map(|_| ())
+    }
+
+    /// Caches a miss: until `ttl` passes, `get_entry` returns `ShardEntry::Absent` for this key.
+    pub fn insert_absent(
+        &mut self,
+        key: Key,
+        weight: u64,
+        ttl: Duration,
+    ) -> Result<(), ShardError> {
+        let entry = NewEntry {
+            expires_at: now_millis().saturating_add(ttl.as_millis() as u64),
+            negative: true,
+            ..NewEntry::new(Vec::new(), weight)
+        };
+        self.insert_node(key, entry).map(|_| ())
+    }
+
+    /// Updates the value of the entry only if its current version is `expected_version`,
+    /// its tags, cost and expiration time are kept. Returns the new version, or `None` if the key is missing
+    /// or its version differs.
+    pub fn compare_and_set(
+        &mut self,
+        key: Key,
+        expected_version: u64,
+        data_size: u64,
+        data: Vec<u8>,
+    ) -> Result<Option<u64>, ShardError> {
+        let Some(idx) = self.find_idx(&key) else {
+            return Ok(None);
+        };
+        if !self.is_resident(idx)
+            || self.is_expired(idx)
+            || self.nodes[idx].version != expected_version
+        {
+            return Ok(None);
+        }
+        let entry = self.value_update(idx, data, data_size);
+        self.insert_node(key, entry).map(Some)
+    }
+
+    // Replaces only the value of the entry at `idx`, keeping its tags, cost and expiration
+    fn value_update(&self, idx: usize, data: Vec<u8>, data_size: u64) -> NewEntry {
+        NewEntry {
+            cost: self.nodes[idx].cost,
+            expires_at: self.nodes[idx].expires_at,
+            tags: self.tags.tags(idx as u32).to_vec(),
+            ..NewEntry::new(data, data_size)
+        }
+    }
+
+    // Returns version assigned to the entry
+    #[inline(always)]
+    fn insert_node(&mut self, key: Key, entry: NewEntry) -> Result<u64, ShardError> {
+        let NewEntry {
+            data,
+            weight: data_size,
+            cost,
+            expires_at,
+            negative,
+            pinned,
+            tags,
+        } = entry;
+        self.decay_frequencies_if_due();
+        let version = self.next_version;
+        self.next_version += 1;
         let hash = self.hasher.hash_one(&key);
+        self.record_access(hash);
 
         if let Some(idx) = self
             .map
@@ -172,77 +617,465 @@ impl<Key: Eq + Hash, B: BuildHasher> CacheShard<Key, B> {
             .map(|&idx| idx as usize)
         {
             // update node if it already exists
-            if self.nodes[idx].freq < 3 {
-                self.nodes[idx].freq += 1;
+            self.touch(idx);
+            // new value can be lighter than the old one, so do not compute the difference directly
+            let old_weight = self.nodes[idx].weight;
+            let charged = self.is_charged(idx);
+            if charged {
+                self.discharge(idx);
             }
-            let weight_diff = data_size - self.nodes[idx].weight;
             match self.nodes[idx].queue {
-                QueueTypeId::Small => self.small_size += weight_diff,
-                QueueTypeId::Main => self.main_size += weight_diff,
-                QueueTypeId::Ghost => self.ghost_size += weight_diff,
-                QueueTypeId::NoQueue => {}
+                QueueTypeId::Small => {
+                    self.small_size = self.small_size.saturating_sub(old_weight) + data_size
+                }
+                QueueTypeId::Main => {
+                    self.main_size = self.main_size.saturating_sub(old_weight) + data_size
+                }
+                QueueTypeId::Pinned => {
+                    self.pinned_size = self.pinned_size.saturating_sub(old_weight) + data_size
+                }
+                QueueTypeId::Ghost | QueueTypeId::NoQueue => {}
             }
             self.nodes[idx].data = data;
             self.nodes[idx].weight = data_size;
+            self.nodes[idx].cost = cost;
+            self.nodes[idx].expires_at = expires_at;
+            self.nodes[idx].negative = negative;
+            self.nodes[idx].version = version;
+            self.tags.set(idx as u32, tags);
+            if charged {
+                self.charge(idx);
+            }
+            if pinned {
+                self.pin_node(idx);
+            }
         } else {
-            // otherwise, create a new node, insert it into the map and store the key
-            let new_idx = self.allocate_small(data_size, data).idx;
-            if new_idx as usize == self.nodes_keys.len() {
-                self.nodes_keys.push(key);
+            // otherwise, create a new node, insert it into the map and store the key.
+            // Keys that were evicted from the small queue recently (ghosts) go to the main queue,
+            // unless TinyLFU rejects them again
+            let ghost_hit = self.ghosts.remove(fingerprint(&key)).is_some()
+                && self.admits_hashed(hash, data_size);
+            let new_idx = if pinned {
+                self.allocate_pinned(data_size, data).idx
+            } else if ghost_hit
+                || self.admit_to_main
+                || (self.options.large_entries_bypass_small && data_size > self.small_threshold)
+            {
+                self.allocate_main(data_size, data).idx
             } else {
-                self.nodes_keys[new_idx as usize] = key;
-            }
-            self.map.insert_unique(hash, new_idx, |&idx| {
-                self.hasher.hash_one(&self.nodes_keys[idx as usize])
-            });
+                self.allocate_small(data_size, data).idx
+            };
+            self.nodes[new_idx as usize].cost = cost;
+            self.nodes[new_idx as usize].expires_at = expires_at;
+            self.nodes[new_idx as usize].negative = negative;
+            self.nodes[new_idx as usize].version = version;
+            self.store_key(hash, new_idx, key);
+            self.tags.set(new_idx, tags);
+            self.charge(new_idx as usize);
         }
 
         // if after insertion, we exceed thresholds, evict nodes
-        self.evict_small_if_needed();
-        self.evict_ghost_if_needed();
-        self.evict_main_if_needed();
+        self.evict_if_needed().map(|_| version)
+    }
+
+    /// Evicts nodes from queues that exceed their thresholds.
+    /// If the shard turns out to be inconsistent, it is reset and an error is returned.
+    pub fn evict_if_needed(&mut self) -> Result<(), ShardError> {
+        let evicted = self
+            .evict_over_quota()
+            .and_then(|_| self.evict_small_if_needed())
+            .and_then(|_| self.evict_ghost_if_needed())
+            .and_then(|_| self.evict_main_if_needed());
+        if evicted.is_err() {
+            self.reset();
+        }
+        evicted
+    }
+
+    fn store_key(&mut self, hash: u64, idx: u32, key: Key) {
+        if idx as usize == self.nodes_keys.len() {
+            self.nodes_keys.push(key);
+        } else {
+            self.nodes_keys[idx as usize] = key;
+        }
+        self.map.insert_unique(hash, idx, |&idx| {
+            self.hasher.hash_one(&self.nodes_keys[idx as usize])
+        });
+    }
+
+    /// Copies all entries of the shard, ghosts are copied by `export_ghosts`. Entries of each
+    /// queue are listed from the oldest to the newest, expired ones are skipped.
+    pub fn export(&self) -> Vec<NodeSnapshot<Key>>
+    where
+        Key: Clone,
+    {
+        let mut entries = Vec::with_capacity(self.map.len());
+        self.export_queue(&self.small_head, &mut entries);
+        self.export_queue(&self.main_head, &mut entries);
+        self.export_queue(&self.pinned_head, &mut entries);
+        entries
+    }
+
+    fn export_queue<Q: QueueWithMembers>(
+        &self,
+        head: &QueueHead<Q>,
+        entries: &mut Vec<NodeSnapshot<Key>>,
+    ) where
+        Key: Clone,
+    {
+        let QueueHead::Some(head_ref) = head else {
+            return;
+        };
+        // head is the oldest node, and previous nodes are newer (see `pop_head`)
+        let mut current_idx = head_ref.idx;
+        loop {
+            if !self.is_expired(current_idx as usize) {
+                entries.push(self.snapshot_node(current_idx as usize));
+            }
+            current_idx = self.nodes[current_idx as usize].prev;
+            if current_idx == head_ref.idx {
+                break;
+            }
+        }
+    }
+
+    /// Copies a single entry by key. Returns None if the key is absent or expired.
+    pub fn export_entry<Q: Hash + Equivalent<Key> + ?Sized>(
+        &self,
+        key: &Q,
+    ) -> Option<NodeSnapshot<Key>>
+    where
+        Key: Clone,
+    {
+        let idx = self.find_idx(key)?;
+        if self.is_expired(idx) {
+            return None;
+        }
+        Some(self.snapshot_node(idx))
+    }
+
+    /// Up to `k` most accessed keys matching the filter, with their approximate access
+    /// counts and count errors, the most accessed first. Only keys of live entries are known,
+    /// so keys that are only missed are not reported. Empty if `hot_keys_tracked` is 0.
+    pub fn hot_keys(&self, k: usize, filter: impl Fn(&Key) -> bool) -> Vec<(Key, u64, u64)>
+    where
+        Key: Clone,
+    {
+        let Some(hot_keys) = &self.hot_keys else {
+            return Vec::new();
+        };
+        hot_keys
+            .top()
+            .into_iter()
+            .filter_map(|(fingerprint, count, error)| {
+                let &idx = self.map.find(fingerprint, |&idx| {
+                    self.hasher.hash_one(&self.nodes_keys[idx as usize]) == fingerprint
+                })?;
+                let idx = idx as usize;
+                let key = &self.nodes_keys[idx];
+                (self.is_resident(idx) && !self.is_expired(idx) && filter(key))
+                    .then(|| (key.clone(), count, error))
+            })
+            .take(k)
+            .collect()
+    }
+
+    fn snapshot_node(&self, idx: usize) -> NodeSnapshot<Key>
+    where
+        Key: Clone,
+    {
+        let node = &self.nodes[idx];
+        NodeSnapshot {
+            key: self.nodes_keys[idx].clone(),
+            data: node.data.clone(),
+            weight: node.weight,
+            cost: node.cost,
+            expires_at: node.expires_at,
+            freq: node.freq,
+            queue: node.queue,
+            negative: node.negative,
+            tags: self.tags.tags(idx as u32).to_vec(),
+        }
+    }
+
+    /// Same as `restore`, but replaces the entry if the key is already present.
+    /// Used to replay the operation log, where the newest state of a key wins.
+    pub fn replace(&mut self, entry: NodeSnapshot<Key>) {
+        self.purge(&entry.key);
+        let _ = self.restore(entry);
+    }
+
+    /// Removes the key from the shard, no matter if it is a resident entry or a ghost.
+    /// Returns true if the key was found.
+    pub fn purge<Q: Hash + Equivalent<Key> + ?Sized>(&mut self, key: &Q) -> bool {
+        let hash = self.hasher.hash_one(key);
+        match self.find_idx_hashed(hash, key) {
+            Some(idx) => self.remove(idx),
+            None => self.ghosts.remove(fingerprint(key)).is_some(),
+        }
+    }
+
+    /// Appends an entry from a snapshot to the tail of its queue, keeping its frequency.
+    /// Entries must be restored in the order they were exported, keys that are already
+    /// present are skipped. Call `evict_if_needed` after restoring, in case the shard is
+    /// smaller than the one the snapshot was taken from. Returns false if the entry was
+    /// skipped.
+    ///
+    /// The ghost of the key is forgotten in any case, so ghosts should be restored first.
+    pub fn restore(&mut self, entry: NodeSnapshot<Key>) -> bool {
+        self.ghosts.remove(fingerprint(&entry.key));
+        let hash = self.hasher.hash_one(&entry.key);
+        if self.find_idx_hashed(hash, &entry.key).is_some() {
+            return false;
+        }
+        let weight = entry.weight;
+        let new_node = self.create_node(weight, entry.data);
+        let idx = new_node.idx;
+        let node = &mut self.nodes[idx as usize];
+        node.cost = entry.cost;
+        node.expires_at = entry.expires_at;
+        node.freq = entry.freq.min(self.options.max_frequency);
+        node.negative = entry.negative;
+        node.version = self.next_version;
+        self.next_version += 1;
+
+        match entry.queue {
+            QueueTypeId::Main => {
+                self.main_size += weight;
+                let _ = move_to_queue::<MainQueue>(new_node, &mut self.nodes, &mut self.main_head);
+            }
+            QueueTypeId::Pinned => {
+                self.pinned_size += weight;
+                let _ =
+                    move_to_queue::<PinnedQueue>(new_node, &mut self.nodes, &mut self.pinned_head);
+            }
+            QueueTypeId::Small | QueueTypeId::Ghost | QueueTypeId::NoQueue
+                if self.admit_to_main =>
+            {
+                self.main_size += weight;
+                let _ = move_to_queue::<MainQueue>(new_node, &mut self.nodes, &mut self.main_head);
+            }
+            QueueTypeId::Small | QueueTypeId::Ghost | QueueTypeId::NoQueue => {
+                self.small_size += weight;
+                let _ =
+                    move_to_queue::<SmallQueue>(new_node, &mut self.nodes, &mut self.small_head);
+            }
+        }
+        self.store_key(hash, idx, entry.key);
+        self.tags.set(idx, entry.tags);
+        if self.is_charged(idx as usize) {
+            self.charge(idx as usize);
+        }
+        true
+    }
+
+    /// Copies the ghosts of the shard (fingerprint of the key and weight), from the oldest
+    /// to the newest. Fingerprints don't depend on the hasher of the shard.
+    pub fn export_ghosts(&self) -> Vec<(u64, u64)> {
+        self.ghosts.export()
+    }
+
+    /// Adds a ghost copied by `export_ghosts`, as the newest one. Restore ghosts before
+    /// entries, which forget the ghosts of their keys, and call `evict_if_needed` after.
+    pub fn restore_ghost(&mut self, fingerprint: u64, weight: u64) {
+        self.ghosts.push(fingerprint, weight);
     }
 
     /// Deletes (deallocates) a cache entry by key.
     /// Returns true if the node was found and deleted, false otherwise.
-    pub fn delete(&mut self, key: &Key) -> bool {
-        let hash = self.hasher.hash_one(key);
-        let Some(idx) = self
+    pub fn delete<Q: Hash + Equivalent<Key> + ?Sized>(&mut self, key: &Q) -> bool {
+        let Some(idx) = self.find_idx(key) else {
+            return false;
+        };
+
+        // nodes without data can't be read, they are not entries
+        if !self.is_resident(idx) {
+            return false;
+        }
+
+        self.remove(idx)
+    }
+
+    /// Removes entries whose keys don't satisfy `keep`. Returns keys of the removed entries.
+    /// Ghosts are kept, since their keys are not known.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Key) -> bool) -> Vec<Key>
+    where
+        Key: Clone,
+    {
+        let removed: Vec<usize> = self
             .map
-            .find(hash, |&idx| self.nodes_keys[idx as usize] == *key)
-            .map(|idx| (*idx) as usize)
-        else {
+            .iter()
+            .map(|&idx| idx as usize)
+            .filter(|&idx| !keep(&self.nodes_keys[idx]))
+            .collect();
+        let mut removed_keys = Vec::new();
+        for idx in removed {
+            if self.is_resident(idx) {
+                removed_keys.push(self.nodes_keys[idx].clone());
+            }
+            self.remove(idx);
+        }
+        removed_keys
+    }
+
+    /// Removes all entries carrying the tag. Returns keys of the removed entries.
+    pub fn invalidate_tag(&mut self, tag: &str) -> Vec<Key>
+    where
+        Key: Clone,
+    {
+        let tagged = self.tags.nodes(tag);
+        let mut removed_keys = Vec::with_capacity(tagged.len());
+        for idx in tagged {
+            removed_keys.push(self.nodes_keys[idx as usize].clone());
+            self.remove(idx as usize);
+        }
+        removed_keys
+    }
+
+    /// Returns number and total weight of live entries whose keys satisfy `filter`.
+    pub fn usage(&self, mut filter: impl FnMut(&Key) -> bool) -> (u64, u64) {
+        let mut entries = 0;
+        let mut weight = 0;
+        for &idx in self.map.iter() {
+            let idx = idx as usize;
+            if self.is_resident(idx) && !self.is_expired(idx) && filter(&self.nodes_keys[idx]) {
+                entries += 1;
+                weight += self.nodes[idx].weight;
+            }
+        }
+        (entries, weight)
+    }
+
+    // Counts an access of the node, as the eviction policy says
+    fn touch(&mut self, idx: usize) {
+        let policy = &self.options.eviction_policy;
+        self.nodes[idx].freq = policy
+            .on_access(self.nodes[idx].freq)
+            .min(self.options.max_frequency);
+        if policy.moves_to_tail() && self.nodes[idx].queue == QueueTypeId::Main {
+            let node_ref = get_node_ref::<MainQueue>(idx, &self.nodes);
+            let detached = detach_node(node_ref, &mut self.main_head, &mut self.nodes);
+            let _ = move_to_queue::<MainQueue>(detached, &mut self.nodes, &mut self.main_head);
+        }
+    }
+
+    // Removes node from its queue and frees it
+    fn remove(&mut self, idx: usize) -> bool {
+        let Some(detached) = self.detach(idx) else {
+            return false;
+        };
+        let freed_ref = evict_node(detached, &mut self.nodes);
+        self.handle_node_eviction(freed_ref);
+        true
+    }
+
+    // Node holds an entry: data or a cached miss
+    fn is_resident(&self, idx: usize) -> bool {
+        let node = &self.nodes[idx];
+        !node.data.is_empty() || node.negative
+    }
+
+    fn is_expired(&self, idx: usize) -> bool {
+        let expires_at = self.nodes[idx].expires_at;
+        expires_at != 0 && expires_at <= now_millis()
+    }
+
+    /// Pins an existing entry, so it is never evicted until unpinned.
+    /// Returns false if the entry was not found (ghosts can't be pinned, they have no data).
+    pub fn pin<Q: Hash + Equivalent<Key> + ?Sized>(&mut self, key: &Q) -> bool {
+        let Some(idx) = self.find_idx(key) else {
             return false;
         };
+        if !self.is_resident(idx) {
+            return false;
+        }
+        self.pin_node(idx);
+        true
+    }
 
-        // check if node is occupied (has data)
-        if self.nodes[idx].data.len() <= 0 {
+    /// Moves a pinned entry to the main queue, where it is subject to eviction again.
+    /// Returns false if the entry was not found or is not pinned.
+    pub fn unpin<Q: Hash + Equivalent<Key> + ?Sized>(&mut self, key: &Q) -> bool {
+        let Some(idx) = self.find_idx(key) else {
+            return false;
+        };
+        if self.nodes[idx].queue != QueueTypeId::Pinned {
             return false;
         }
+        let Some(detached) = self.detach(idx) else {
+            return false;
+        };
+        // main queue may be over budget now, it will be trimmed by the next insert
+        self.promote_to_main(detached);
+        true
+    }
+
+    fn find_idx<Q: Hash + Equivalent<Key> + ?Sized>(&self, key: &Q) -> Option<usize> {
+        self.find_idx_hashed(self.hasher.hash_one(key), key)
+    }
+
+    fn find_idx_hashed<Q: Equivalent<Key> + ?Sized>(&self, hash: u64, key: &Q) -> Option<usize> {
+        self.map
+            .find(hash, |&idx| key.equivalent(&self.nodes_keys[idx as usize]))
+            .map(|&idx| idx as usize)
+    }
 
-        // remove node from its queue and update size
-        match self.nodes[idx].queue {
+    /// Unlinks node from its queue and subtracts its weight from the queue size.
+    /// Returns None if the node is not part of any queue.
+    fn detach(&mut self, idx: usize) -> Option<NodeRef<NoQueue, Occupied>> {
+        let weight = self.nodes[idx].weight;
+        if self.is_charged(idx) {
+            self.discharge(idx);
+        }
+        let detached = match self.nodes[idx].queue {
             QueueTypeId::Small => {
-                self.small_size -= self.nodes[idx].weight;
+                self.small_size = self.small_size.saturating_sub(weight);
                 let node_ref = get_node_ref::<SmallQueue>(idx, &self.nodes);
-                let freed_ref = delete_node(node_ref, &mut self.small_head, &mut self.nodes);
-                self.handle_node_eviction(freed_ref);
+                detach_node(node_ref, &mut self.small_head, &mut self.nodes)
             }
             QueueTypeId::Main => {
-                self.main_size -= self.nodes[idx].weight;
+                self.main_size = self.main_size.saturating_sub(weight);
                 let node_ref = get_node_ref::<MainQueue>(idx, &self.nodes);
-                let freed_ref = delete_node(node_ref, &mut self.main_head, &mut self.nodes);
-                self.handle_node_eviction(freed_ref);
+                detach_node(node_ref, &mut self.main_head, &mut self.nodes)
             }
-            QueueTypeId::Ghost => {
-                self.ghost_size -= self.nodes[idx].weight;
-                let node_ref = get_node_ref::<GhostQueue>(idx, &self.nodes);
-                let freed_ref = delete_node(node_ref, &mut self.ghost_head, &mut self.nodes);
-                self.handle_node_eviction(freed_ref);
+            QueueTypeId::Pinned => {
+                self.pinned_size = self.pinned_size.saturating_sub(weight);
+                let node_ref = get_node_ref::<PinnedQueue>(idx, &self.nodes);
+                detach_node(node_ref, &mut self.pinned_head, &mut self.nodes)
             }
-            QueueTypeId::NoQueue => return false,
+            QueueTypeId::Ghost | QueueTypeId::NoQueue => return None,
+        };
+        Some(detached)
+    }
+
+    fn pin_node(&mut self, idx: usize) {
+        if self.nodes[idx].queue == QueueTypeId::Pinned {
+            return;
+        }
+        if let Some(detached) = self.detach(idx) {
+            self.pinned_size += self.nodes[idx].weight;
+            let _ = move_to_queue::<PinnedQueue>(detached, &mut self.nodes, &mut self.pinned_head);
+            self.charge(idx);
         }
-        true
+    }
+
+    // Pinned entries are never evicted, so small and main queues share what's left of the
+    // budget, in the same proportion as their thresholds.
+    fn small_budget(&self) -> u64 {
+        self.budget(self.small_threshold)
+    }
+
+    fn main_budget(&self) -> u64 {
+        self.budget(self.main_threshold)
+    }
+
+    fn budget(&self, threshold: u64) -> u64 {
+        if self.pinned_size == 0 {
+            return threshold;
+        }
+        let total = self.small_threshold + self.main_threshold;
+        let remaining = total.saturating_sub(self.pinned_size);
+        (threshold as u128 * remaining as u128 / total as u128) as u64
     }
 
     fn allocate_small(&mut self, data_size: u64, data: Vec<u8>) -> NodeRef<SmallQueue, Occupied> {
@@ -251,84 +1084,327 @@ impl<Key: Eq + Hash, B: BuildHasher> CacheShard<Key, B> {
         move_to_queue::<SmallQueue>(new_node, &mut self.nodes, &mut self.small_head)
     }
 
+    fn allocate_main(&mut self, data_size: u64, data: Vec<u8>) -> NodeRef<MainQueue, Occupied> {
+        let new_node = self.create_node(data_size, data);
+        self.main_size += data_size;
+        move_to_queue::<MainQueue>(new_node, &mut self.nodes, &mut self.main_head)
+    }
+
+    fn allocate_pinned(&mut self, data_size: u64, data: Vec<u8>) -> NodeRef<PinnedQueue, Occupied> {
+        let new_node = self.create_node(data_size, data);
+        self.pinned_size += data_size;
+        move_to_queue::<PinnedQueue>(new_node, &mut self.nodes, &mut self.pinned_head)
+    }
+
     /// If small queue exceeds threshold, evict nodes from the head of the small queue:
-    /// - if node has freq > 0, promote it to main queue
-    /// - if node has freq == 0, demote it to ghost queue
-    fn evict_small_if_needed(&mut self) {
-        // TODO: maybe remove code duplication with `evict_ghost` and `evict_main`
-        while self.small_size > self.small_threshold {
-            if let Some(detached_head) = pop_head(&mut self.nodes, &mut self.small_head) {
-                self.small_size -= self.nodes[detached_head.idx as usize].weight;
-                if self.nodes[detached_head.idx as usize].freq > 0 {
+    /// - if node has freq > 0 (or its namespace is below its minimum), promote it to main queue,
+    ///   unless TinyLFU admission is enabled and rejects it
+    /// - otherwise, demote it to ghost queue (cheapest nodes near the head first)
+    fn evict_small_if_needed(&mut self) -> Result<(), ShardError> {
+        // TODO: maybe remove code duplication with `evict_main`
+        while self.small_size > self.small_budget() {
+            if let Some(detached_head) = pop_victim(&mut self.nodes, &mut self.small_head) {
+                self.small_size = self
+                    .small_size
+                    .saturating_sub(self.nodes[detached_head.idx as usize].weight);
+                let protected = self.is_protected(detached_head.idx as usize);
+                self.discharge(detached_head.idx as usize);
+                let idx = detached_head.idx as usize;
+                if (self.nodes[idx].freq > 0 && self.admits(idx)) || protected {
                     self.promote_to_main(detached_head);
                 } else {
                     self.demote_to_ghost(detached_head);
                 }
             } else {
-                // TODO: remove panic? (here and in other evict's)
-                panic!("Tried to evict from small queue, but it is empty (Head of small is None)");
+                return Err(ShardError::EmptyQueue(QueueTypeId::Small));
             }
         }
+        Ok(())
     }
 
-    /// If ghost queue exceeds threshold, evict nodes from the head of the ghost queue:
-    /// - if node has freq > 0 and has data, promote it to main queue
-    /// - otherwise, evict it
-    fn evict_ghost_if_needed(&mut self) {
-        while self.ghost_size > self.ghost_threshold {
-            if let Some(detached_head) = pop_head(&mut self.nodes, &mut self.ghost_head) {
-                self.ghost_size -= self.nodes[detached_head.idx as usize].weight;
-                if self.nodes[detached_head.idx as usize].freq > 0
-                    && self.nodes[detached_head.idx as usize].data.len() > 0
-                {
-                    self.promote_to_main(detached_head);
-                } else {
-                    let freed_ref = evict_node(detached_head, &mut self.nodes);
-                    self.handle_node_eviction(freed_ref);
-                }
-            } else {
-                panic!("Tried to evict from ghost queue, but it is empty (Head of ghost is None)");
+    // TinyLFU admission: an entry leaving the small queue displaces the oldest entry of
+    // a full main queue only if its key was accessed more often
+    fn admits(&self, idx: usize) -> bool {
+        self.admits_hashed(
+            self.hasher.hash_one(&self.nodes_keys[idx]),
+            self.nodes[idx].weight,
+        )
+    }
+
+    fn admits_hashed(&self, hash: u64, weight: u64) -> bool {
+        let (Some(sketch), QueueHead::Some(victim)) = (&self.sketch, &self.main_head) else {
+            return true;
+        };
+        if self.main_size + weight <= self.main_budget() {
+            return true;
+        }
+        let candidate = sketch.estimate(hash);
+        let victim = sketch.estimate(self.hasher.hash_one(&self.nodes_keys[victim.idx as usize]));
+        candidate > victim
+    }
+
+    #[inline(always)]
+    fn record_access(&mut self, hash: u64) {
+        if let Some(sketch) = &mut self.sketch {
+            sketch.increment(hash, self.map.len());
+        }
+        if let Some(hot_keys) = &mut self.hot_keys {
+            hot_keys.record(hash);
+        }
+    }
+
+    /// If ghost queue exceeds threshold, forget the oldest ghosts
+    fn evict_ghost_if_needed(&mut self) -> Result<(), ShardError> {
+        while self.ghosts.size() > self.ghost_threshold {
+            if self.ghosts.pop_oldest().is_none() {
+                return Err(ShardError::EmptyQueue(QueueTypeId::Ghost));
             }
         }
+        Ok(())
     }
 
     /// If main queue exceeds threshold, evict nodes from the head of the main queue:
-    /// - if node has freq > 0, reinsert it back to main queue (with freq - 1)
-    /// - otherwise, evict it
-    fn evict_main_if_needed(&mut self) {
-        while self.main_size > self.main_threshold {
-            if let Some(detached_head) = pop_head(&mut self.nodes, &mut self.main_head) {
-                if self.nodes[detached_head.idx as usize].freq > 0 {
+    /// - if the eviction policy gives it a second chance, reinsert it back to main queue
+    ///   (for S3-FIFO, if node has freq > 0, with freq - 1)
+    /// - if its namespace is below its minimum, reinsert it as well, unless every node
+    ///   of the queue turns out to be protected this way
+    /// - otherwise, evict it (cheapest nodes near the head first)
+    fn evict_main_if_needed(&mut self) -> Result<(), ShardError> {
+        let mut protected_weight = 0;
+        while self.main_size > self.main_budget() {
+            if let Some(detached_head) = pop_victim(&mut self.nodes, &mut self.main_head) {
+                let idx = detached_head.idx as usize;
+                let protected = protected_weight < self.main_size && self.is_protected(idx);
+                let second_chance = self
+                    .options
+                    .eviction_policy
+                    .second_chance(self.nodes[idx].freq);
+                if second_chance.is_some() || protected {
                     // reinsert back to main queue
-                    self.nodes[detached_head.idx as usize].freq -= 1;
+                    if protected {
+                        protected_weight += self.nodes[idx].weight;
+                    }
+                    self.nodes[idx].freq = second_chance.unwrap_or(0);
                     let _ = move_to_queue::<MainQueue>(
                         detached_head,
                         &mut self.nodes,
                         &mut self.main_head,
                     );
                 } else {
-                    self.main_size -= self.nodes[detached_head.idx as usize].weight;
+                    self.main_size = self
+                        .main_size
+                        .saturating_sub(self.nodes[detached_head.idx as usize].weight);
+                    self.discharge(detached_head.idx as usize);
+                    self.evictions += 1;
                     let freed_ref = evict_node(detached_head, &mut self.nodes);
                     self.handle_node_eviction(freed_ref);
                 }
             } else {
-                panic!("Tried to evict from main queue, but it is empty (Head of main is None)");
+                return Err(ShardError::EmptyQueue(QueueTypeId::Main));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets weight limits of a namespace in this shard, `None` removes them.
+    /// If the namespace is over its new maximum, its entries are evicted right away.
+    pub fn set_quota(
+        &mut self,
+        namespace: NamespaceId,
+        quota: Option<NamespaceQuota>,
+    ) -> Result<(), ShardError> {
+        let Some(quota) = quota else {
+            self.quotas.remove(&namespace);
+            return Ok(());
+        };
+        let (mut weight, mut pinned) = (0, 0);
+        for idx in self.map.iter().map(|&idx| idx as usize) {
+            if self.is_charged(idx) && self.nodes_keys[idx].namespace() == namespace {
+                weight += self.nodes[idx].weight;
+                if self.nodes[idx].queue == QueueTypeId::Pinned {
+                    pinned += self.nodes[idx].weight;
+                }
+            }
+        }
+        self.quotas.insert(
+            namespace,
+            ShardQuota {
+                quota,
+                weight,
+                pinned,
+            },
+        );
+        self.evict_if_needed()
+    }
+
+    /// Returns weight of the entries of a namespace with a quota (in small, main and pinned queues).
+    pub fn namespace_weight(&self, namespace: NamespaceId) -> Option<u64> {
+        self.quotas.get(&namespace).map(|quota| quota.weight)
+    }
+
+    // Namespace weight counts nodes of small, main and pinned queues: ghosts don't hold data.
+    // Nodes are charged after they join one of these queues and discharged before they leave it.
+    fn is_charged(&self, idx: usize) -> bool {
+        matches!(
+            self.nodes[idx].queue,
+            QueueTypeId::Small | QueueTypeId::Main | QueueTypeId::Pinned
+        )
+    }
+
+    fn charge(&mut self, idx: usize) {
+        if self.quotas.is_empty() {
+            return;
+        }
+        if let Some(quota) = self.quotas.get_mut(&self.nodes_keys[idx].namespace()) {
+            quota.weight += self.nodes[idx].weight;
+            if self.nodes[idx].queue == QueueTypeId::Pinned {
+                quota.pinned += self.nodes[idx].weight;
+            }
+        }
+    }
+
+    fn discharge(&mut self, idx: usize) {
+        if self.quotas.is_empty() {
+            return;
+        }
+        if let Some(quota) = self.quotas.get_mut(&self.nodes_keys[idx].namespace()) {
+            quota.weight = quota.weight.saturating_sub(self.nodes[idx].weight);
+            if self.nodes[idx].queue == QueueTypeId::Pinned {
+                quota.pinned = quota.pinned.saturating_sub(self.nodes[idx].weight);
             }
         }
     }
 
+    // Evicting the node would take its namespace below the guaranteed minimum
+    fn is_protected(&self, idx: usize) -> bool {
+        if self.quotas.is_empty() {
+            return false;
+        }
+        self.quotas
+            .get(&self.nodes_keys[idx].namespace())
+            .is_some_and(|quota| quota.weight < quota.quota.min_weight + self.nodes[idx].weight)
+    }
+
+    /// Evicts the oldest entries of namespaces that exceed their maximum weight, before
+    /// anything else is evicted. Small queue entries are demoted to the ghost queue as usual.
+    /// Finding them walks the queues, so quotas are meant for a limited number of namespaces.
+    /// Pinned entries don't count against the maximum, evicting others can't make up for them.
+    fn evict_over_quota(&mut self) -> Result<(), ShardError> {
+        if self.quotas.is_empty() {
+            return Ok(());
+        }
+        let over_quota: Vec<NamespaceId> = self
+            .quotas
+            .iter()
+            .filter(|(_, quota)| quota.is_over())
+            .map(|(&namespace, _)| namespace)
+            .collect();
+        for namespace in over_quota {
+            let mut victims = self.namespace_members(&self.small_head, namespace);
+            victims.extend(self.namespace_members(&self.main_head, namespace));
+            for idx in victims {
+                if !self.quotas[&namespace].is_over() {
+                    break;
+                }
+                let queue = self.nodes[idx].queue;
+                let Some(detached) = self.detach(idx) else {
+                    return Err(ShardError::EmptyQueue(queue));
+                };
+                if queue == QueueTypeId::Small {
+                    self.demote_to_ghost(detached);
+                } else {
+                    self.evictions += 1;
+                    let freed_ref = evict_node(detached, &mut self.nodes);
+                    self.handle_node_eviction(freed_ref);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Nodes of the namespace in the queue, from the oldest to the newest
+    fn namespace_members<Q: QueueWithMembers>(
+        &self,
+        head: &QueueHead<Q>,
+        namespace: NamespaceId,
+    ) -> Vec<usize> {
+        let mut members = Vec::new();
+        let QueueHead::Some(head_ref) = head else {
+            return members;
+        };
+        let mut current_idx = head_ref.idx;
+        loop {
+            if self.nodes_keys[current_idx as usize].namespace() == namespace {
+                members.push(current_idx as usize);
+            }
+            current_idx = self.nodes[current_idx as usize].prev;
+            if current_idx == head_ref.idx {
+                break;
+            }
+        }
+        members
+    }
+
+    /// Drops all entries and brings the shard back to its initial (consistent) state.
+    /// Thresholds and allocated capacity are kept.
+    pub fn reset(&mut self) {
+        self.map.clear();
+        self.nodes_keys.clear();
+        self.nodes.clear();
+        self.freelist.clear();
+        self.small_size = 0;
+        self.main_size = 0;
+        self.pinned_size = 0;
+        self.small_head = QueueHead::None;
+        self.main_head = QueueHead::None;
+        self.pinned_head = QueueHead::None;
+        self.ghosts.clear();
+        self.evictions = 0;
+        for quota in self.quotas.values_mut() {
+            quota.weight = 0;
+            quota.pinned = 0;
+        }
+        self.tags.clear();
+        if let Some(sketch) = &mut self.sketch {
+            sketch.clear();
+        }
+        if let Some(hot_keys) = &mut self.hot_keys {
+            hot_keys.clear();
+        }
+        self.last_decay = now_millis();
+    }
+
+    // Halves frequencies of all entries if `frequency_decay_interval` passed since the last time
+    fn decay_frequencies_if_due(&mut self) {
+        let Some(interval) = self.options.frequency_decay_interval else {
+            return;
+        };
+        let now = now_millis();
+        if now.saturating_sub(self.last_decay) < interval.as_millis() as u64 {
+            return;
+        }
+        self.last_decay = now;
+        for node in &mut self.nodes {
+            node.freq /= 2;
+        }
+    }
+
     fn promote_to_main(&mut self, node_ref: NodeRef<NoQueue, Occupied>) {
-        self.nodes[node_ref.idx as usize].freq = 0;
-        self.main_size += self.nodes[node_ref.idx as usize].weight;
+        let idx = node_ref.idx as usize;
+        self.nodes[idx].freq = 0;
+        self.main_size += self.nodes[idx].weight;
         let _ = move_to_queue::<MainQueue>(node_ref, &mut self.nodes, &mut self.main_head);
+        self.charge(idx);
     }
 
+    // Frees the node, only the fingerprint of its key and its weight are remembered
     fn demote_to_ghost(&mut self, node_ref: NodeRef<NoQueue, Occupied>) {
-        self.ghost_size += self.nodes[node_ref.idx as usize].weight;
-        let ghost_ref =
-            move_to_queue::<GhostQueue>(node_ref, &mut self.nodes, &mut self.ghost_head);
-        self.nodes[ghost_ref.idx as usize].data = Vec::new(); // Drop data for ghost nodes
-        // do not reset data_size (used to calculate ghost_size)
+        self.evictions += 1;
+        let idx = node_ref.idx as usize;
+        self.ghosts
+            .push(fingerprint(&self.nodes_keys[idx]), self.nodes[idx].weight);
+        let freed_ref = evict_node(node_ref, &mut self.nodes);
+        self.handle_node_eviction(freed_ref);
     }
 
     fn create_node(&mut self, data_size: u64, data: Vec<u8>) -> NodeRef<NoQueue, Occupied> {
@@ -344,8 +1420,12 @@ impl<Key: Eq + Hash, B: BuildHasher> CacheShard<Key, B> {
                 prev: new_idx,
                 data,
                 weight: data_size,
+                cost: 0,
+                expires_at: 0,
+                version: 0,
                 freq: 0,
                 queue: QueueTypeId::NoQueue,
+                negative: false,
             });
             new_idx
         };
@@ -358,7 +1438,8 @@ impl<Key: Eq + Hash, B: BuildHasher> CacheShard<Key, B> {
     }
 
     fn handle_node_eviction(&mut self, node_ref: NodeRef<NoQueue, Free>) {
-        // remove associated key
+        // remove associated tags and key
+        self.tags.remove(node_ref.idx);
         let hash = self
             .hasher
             .hash_one(&self.nodes_keys[node_ref.idx as usize]);
@@ -370,10 +1451,219 @@ impl<Key: Eq + Hash, B: BuildHasher> CacheShard<Key, B> {
         self.freelist.push(node_ref);
     }
 
-    pub fn print_queues(&self, truncate_count: usize) {
-        self.print_queue("Small", &self.small_head, truncate_count);
-        self.print_queue("Main", &self.main_head, truncate_count);
-        self.print_queue("Ghost", &self.ghost_head, truncate_count);
+    /// Checks internal invariants of the shard: links and membership of queue nodes,
+    /// size counters, map entries and the freelist. Walks every node, so it is meant for
+    /// tests and diagnostics rather than the hot path.
+    pub fn validate(&self) -> ShardReport {
+        let mut report = ShardReport::default();
+        // queue each node was reached from
+        let mut reached = vec![QueueTypeId::NoQueue; self.nodes.len()];
+        (report.small_entries, report.small_size) = self.validate_queue(
+            QueueTypeId::Small,
+            self.small_head.idx(),
+            &mut reached,
+            &mut report.problems,
+        );
+        (report.main_entries, report.main_size) = self.validate_queue(
+            QueueTypeId::Main,
+            self.main_head.idx(),
+            &mut reached,
+            &mut report.problems,
+        );
+        (report.pinned_entries, report.pinned_size) = self.validate_queue(
+            QueueTypeId::Pinned,
+            self.pinned_head.idx(),
+            &mut reached,
+            &mut report.problems,
+        );
+        for (queue, counted, recomputed) in [
+            (QueueTypeId::Small, self.small_size, report.small_size),
+            (QueueTypeId::Main, self.main_size, report.main_size),
+            (QueueTypeId::Pinned, self.pinned_size, report.pinned_size),
+        ] {
+            if counted != recomputed {
+                report.problems.push(ShardProblem::SizeMismatch {
+                    queue,
+                    counted,
+                    recomputed,
+                });
+            }
+        }
+
+        report.ghosts = self.ghosts.len();
+        report.ghost_size = self.ghosts.recount_size();
+        if self.ghosts.size() != report.ghost_size {
+            report.problems.push(ShardProblem::SizeMismatch {
+                queue: QueueTypeId::Ghost,
+                counted: self.ghosts.size(),
+                recomputed: report.ghost_size,
+            });
+        }
+
+        let mut free = vec![false; self.nodes.len()];
+        for freed in &self.freelist {
+            let idx = freed.idx as usize;
+            if reached.get(idx) != Some(&QueueTypeId::NoQueue) || free[idx] {
+                report
+                    .problems
+                    .push(ShardProblem::ReachableFreeNode { idx: freed.idx });
+            } else {
+                free[idx] = true;
+            }
+        }
+        report.free_nodes = free.iter().filter(|&&free| free).count();
+
+        let mut mapped = vec![false; self.nodes.len()];
+        for &idx in self.map.iter() {
+            if reached
+                .get(idx as usize)
+                .is_none_or(|&queue| queue == QueueTypeId::NoQueue)
+            {
+                report.problems.push(ShardProblem::DanglingMapEntry { idx });
+                continue;
+            }
+            mapped[idx as usize] = true;
+            let hash = self.hasher.hash_one(&self.nodes_keys[idx as usize]);
+            if self.map.find(hash, |&other| other == idx).is_none() {
+                report
+                    .problems
+                    .push(ShardProblem::MisplacedMapEntry { idx });
+            }
+        }
+        for idx in 0..self.nodes.len() {
+            if reached[idx] != QueueTypeId::NoQueue && !mapped[idx] {
+                report
+                    .problems
+                    .push(ShardProblem::UnmappedNode { idx: idx as u32 });
+            } else if reached[idx] == QueueTypeId::NoQueue && !free[idx] {
+                report
+                    .problems
+                    .push(ShardProblem::LeakedNode { idx: idx as u32 });
+            }
+        }
+
+        let mut namespace_weights = HashMap::new();
+        for (idx, &queue) in reached.iter().enumerate() {
+            let namespace = self.nodes_keys.get(idx).map(Namespaced::namespace);
+            if let Some(namespace) = namespace
+                && queue != QueueTypeId::NoQueue
+                && self.quotas.contains_key(&namespace)
+            {
+                *namespace_weights.entry(namespace).or_insert(0) += self.nodes[idx].weight;
+            }
+        }
+        for (&namespace, quota) in &self.quotas {
+            let recomputed = namespace_weights.get(&namespace).copied().unwrap_or(0);
+            if quota.weight != recomputed {
+                report.problems.push(ShardProblem::QuotaMismatch {
+                    namespace,
+                    counted: quota.weight,
+                    recomputed,
+                });
+            }
+        }
+        report
+    }
+
+    // Walks the queue from its head, marks the nodes as reached from it.
+    // Returns the number of nodes and their total weight.
+    fn validate_queue(
+        &self,
+        queue: QueueTypeId,
+        head: Option<u32>,
+        reached: &mut [QueueTypeId],
+        problems: &mut Vec<ShardProblem>,
+    ) -> (usize, u64) {
+        let (mut entries, mut weight) = (0, 0);
+        let Some(head) = head else {
+            return (entries, weight);
+        };
+        let mut idx = head;
+        loop {
+            if reached.get(idx as usize) != Some(&QueueTypeId::NoQueue) {
+                problems.push(ShardProblem::Unterminated { queue, idx });
+                break;
+            }
+            reached[idx as usize] = queue;
+            let node = &self.nodes[idx as usize];
+            entries += 1;
+            weight += node.weight;
+            if node.queue != queue {
+                problems.push(ShardProblem::WrongQueue {
+                    queue,
+                    idx,
+                    found: node.queue,
+                });
+            }
+            let next = node.next;
+            if self
+                .nodes
+                .get(next as usize)
+                .is_none_or(|next| next.prev != idx)
+            {
+                problems.push(ShardProblem::BrokenLink { queue, idx });
+            }
+            if next == head {
+                break;
+            }
+            idx = next;
+        }
+        (entries, weight)
+    }
+
+    /// Describes the queues and the memory of the shard, with up to `keys_per_queue` oldest
+    /// entries of each queue. Walks the queues to count their entries.
+    pub fn inspect(&self, keys_per_queue: usize) -> ShardInfo<Key>
+    where
+        Key: Clone,
+    {
+        ShardInfo {
+            small: self.inspect_queue(self.small_head.idx(), self.small_size, keys_per_queue),
+            main: self.inspect_queue(self.main_head.idx(), self.main_size, keys_per_queue),
+            pinned: self.inspect_queue(self.pinned_head.idx(), self.pinned_size, keys_per_queue),
+            ghosts: self.ghosts.len(),
+            ghost_size: self.ghosts.size(),
+            small_threshold: self.small_threshold,
+            main_threshold: self.main_threshold,
+            ghost_threshold: self.ghost_threshold,
+            keys: self.map.len(),
+            free_nodes: self.freelist.len(),
+            node_capacity: self.nodes.capacity(),
+            evictions: self.evictions,
+        }
+    }
+
+    fn inspect_queue(&self, head: Option<u32>, size: u64, keys: usize) -> QueueInfo<Key>
+    where
+        Key: Clone,
+    {
+        let mut queue = QueueInfo {
+            len: 0,
+            size,
+            oldest: Vec::new(),
+        };
+        let Some(head) = head else {
+            return queue;
+        };
+        // from the head (oldest) through `prev` links to the newest
+        let mut idx = head;
+        loop {
+            if queue.oldest.len() < keys {
+                let node = &self.nodes[idx as usize];
+                queue.oldest.push(QueueEntryInfo {
+                    key: self.nodes_keys[idx as usize].clone(),
+                    freq: node.freq,
+                    weight: node.weight,
+                });
+            }
+            queue.len += 1;
+            idx = self.nodes[idx as usize].prev;
+            // a broken queue would never get back to its head, see `validate`
+            if idx == head || queue.len >= self.nodes.len() {
+                break;
+            }
+        }
+        queue
     }
 
     pub fn get_small_size(&self) -> u64 {
@@ -385,78 +1675,30 @@ impl<Key: Eq + Hash, B: BuildHasher> CacheShard<Key, B> {
     }
 
     pub fn get_ghost_size(&self) -> u64 {
-        self.ghost_size
+        self.ghosts.size()
     }
 
-    fn print_queue(
-        &self,
-        queue_name: &str,
-        head: &QueueHead<impl QueueWithMembers + Copy>,
-        truncate_count: usize,
-    ) {
-        let queue_label = format!("{} queue", queue_name);
-        let pad = 12;
-
-        println!("\n{:->width$}", "", width = pad + 30);
-
-        match head {
-            QueueHead::None => {
-                println!("{:<pad$}[empty]", queue_label, pad = pad);
-                println!("count: 0");
-            }
-            QueueHead::Some(start) => {
-                let mut current_idx = start.idx;
-                let mut out = Vec::new();
-                let mut count = 0;
-
-                loop {
-                    if count <= truncate_count {
-                        out.push(format!("{:?}", current_idx));
-                    }
-
-                    current_idx = self.nodes[current_idx as usize].next;
-                    count += 1;
-                    if count > 10_000 {
-                        panic!("Too many elements in queue, something is wrong");
-                    }
-
-                    // If we've looped back to the start, we're done
-                    if current_idx == start.idx {
-                        break;
-                    }
-                }
-
-                // if more than one element, show as: 87 -> 90 -> 89 -*> 87
-                let joined = if out.len() == 1 {
-                    format!("{} -*> {}", out[0], out[0])
-                } else {
-                    let mut s = out[..out.len().min(out.len() - 1)].join(" -> ");
-                    s.push_str(" -*> ");
-                    s.push_str(&out[0]);
-                    s
-                };
+    pub fn get_pinned_size(&self) -> u64 {
+        self.pinned_size
+    }
 
-                println!("{:<pad$}{}", queue_label, joined, pad = pad);
-                if count > truncate_count {
-                    println!("{:>pad$} ... (truncated)", "", pad = pad);
-                }
-                println!("count: {}", count + 1); // +1 because we start at 0
-            }
-        }
-        println!("{:->width$}\n", "", width = pad + 30);
+    /// Number of entries evicted so far (demoted to the ghost queue or dropped from the
+    /// main queue). Deletes, expirations and dropped ghosts are not counted.
+    pub fn get_evictions(&self) -> u64 {
+        self.evictions
     }
 }
 
 // Pop the head of the queue. Unlink the head if it exists, make previous node a new head, and return the unlinked node.
 fn pop_head<Q: QueueWithMembers>(
-    nodes: &mut Vec<Node>,
+    nodes: &mut [Node],
     head: &mut QueueHead<Q>,
 ) -> Option<NodeRef<NoQueue, Occupied>> {
     match head {
         // if head is Some, unlink it and return the unlinked node
         QueueHead::Some(head_ref) => {
             // if there is a previous node, set it as the new head
-            if let Some(prev_ref) = prev_node(&head_ref, &nodes) {
+            if let Some(prev_ref) = prev_node(head_ref, nodes) {
                 let old_head = std::mem::replace(head_ref, prev_ref); // hacky, it's here because unlink_node consumes NodeRef
                 let unlinked_head = unlink_node(old_head, nodes);
                 Some(unlinked_head)
@@ -477,9 +1719,50 @@ fn pop_head<Q: QueueWithMembers>(
     }
 }
 
+// Pop the node that should leave the queue next. Usually it's the head, but if the head has
+// freq == 0, the cheapest to recompute node with freq == 0 among the oldest `COST_WINDOW` nodes
+// is taken instead. The oldest node wins ties, so without costs this is the same as `pop_head`.
+fn pop_victim<Q: QueueWithMembers>(
+    nodes: &mut [Node],
+    head: &mut QueueHead<Q>,
+) -> Option<NodeRef<NoQueue, Occupied>> {
+    let head_idx = match head {
+        QueueHead::Some(head_ref) => head_ref.idx,
+        QueueHead::None => return None,
+    };
+    if nodes[head_idx as usize].freq > 0 {
+        return pop_head(nodes, head);
+    }
+
+    // nodes closer to the head are older (see `pop_head`)
+    let mut victim_idx = head_idx;
+    let mut current_idx = head_idx;
+    for _ in 1..COST_WINDOW {
+        current_idx = nodes[current_idx as usize].prev;
+        if current_idx == head_idx {
+            break;
+        }
+        let current = &nodes[current_idx as usize];
+        if current.freq == 0 && current.cost < nodes[victim_idx as usize].cost {
+            victim_idx = current_idx;
+        }
+    }
+
+    if victim_idx == head_idx {
+        pop_head(nodes, head)
+    } else {
+        let victim_ref = NodeRef {
+            idx: victim_idx,
+            _occupied: PhantomData,
+            _queue: PhantomData::<Q>,
+        };
+        Some(unlink_node(victim_ref, nodes))
+    }
+}
+
 fn move_to_queue<Q: QueueWithMembers>(
     node_ref: NodeRef<NoQueue, Occupied>,
-    nodes: &mut Vec<Node>,
+    nodes: &mut [Node],
     head: &mut QueueHead<Q>,
 ) -> NodeRef<Q, Occupied> {
     nodes[node_ref.idx as usize].queue = Q::QUEUE_ID;
@@ -511,7 +1794,7 @@ fn move_to_queue<Q: QueueWithMembers>(
 
 fn unlink_node<Q: QueueWithMembers>(
     node_ref: NodeRef<Q, Occupied>,
-    nodes: &mut Vec<Node>,
+    nodes: &mut [Node],
 ) -> NodeRef<NoQueue, Occupied> {
     nodes[node_ref.idx as usize].queue = QueueTypeId::NoQueue;
 
@@ -532,32 +1815,31 @@ fn unlink_node<Q: QueueWithMembers>(
     }
 }
 
-// Evicts node from its queue and frees it
+// Unlinks node from its queue
 // Handles the case when the node is the head of the queue (updating head accordingly)
-fn delete_node<Q: QueueWithMembers>(
+fn detach_node<Q: QueueWithMembers>(
     node_ref: NodeRef<Q, Occupied>,
     head: &mut QueueHead<Q>,
-    nodes: &mut Vec<Node>,
-) -> NodeRef<NoQueue, Free> {
+    nodes: &mut [Node],
+) -> NodeRef<NoQueue, Occupied> {
     let is_head = match head {
         QueueHead::Some(head_ref) => head_ref.idx == node_ref.idx,
         QueueHead::None => false,
     };
     if is_head {
         if let Some(detached_head) = pop_head(nodes, head) {
-            evict_node(detached_head, nodes)
+            detached_head
         } else {
             unreachable!();
         }
     } else {
-        let unlinked = unlink_node(node_ref, nodes);
-        evict_node(unlinked, nodes)
+        unlink_node(node_ref, nodes)
     }
 }
 
 fn prev_node<Q: QueueWithMembers>(
     node_ref: &NodeRef<Q, Occupied>,
-    nodes: &Vec<Node>,
+    nodes: &[Node],
 ) -> Option<NodeRef<Q, Occupied>> {
     if nodes[node_ref.idx as usize].prev == node_ref.idx {
         // if the prev node is itself, it means it's the only node in the queue
@@ -571,12 +1853,13 @@ fn prev_node<Q: QueueWithMembers>(
     })
 }
 
-fn evict_node(
-    node_ref: NodeRef<NoQueue, Occupied>,
-    nodes: &mut Vec<Node>,
-) -> NodeRef<NoQueue, Free> {
+fn evict_node(node_ref: NodeRef<NoQueue, Occupied>, nodes: &mut [Node]) -> NodeRef<NoQueue, Free> {
     nodes[node_ref.idx as usize].data = Vec::new();
     nodes[node_ref.idx as usize].weight = 0;
+    nodes[node_ref.idx as usize].cost = 0;
+    nodes[node_ref.idx as usize].expires_at = 0;
+    nodes[node_ref.idx as usize].version = 0;
+    nodes[node_ref.idx as usize].negative = false;
     nodes[node_ref.idx as usize].freq = 0;
     nodes[node_ref.idx as usize].next = u32::MAX; // set to u32::MAX so any use as an index will panic
     nodes[node_ref.idx as usize].prev = u32::MAX;
@@ -589,12 +1872,16 @@ fn evict_node(
 
 fn occupy_node(
     node_ref: NodeRef<NoQueue, Free>,
-    nodes: &mut Vec<Node>,
+    nodes: &mut [Node],
     data_size: u64,
     data: Vec<u8>,
 ) -> NodeRef<NoQueue, Occupied> {
     nodes[node_ref.idx as usize].data = data;
     nodes[node_ref.idx as usize].weight = data_size;
+    nodes[node_ref.idx as usize].cost = 0;
+    nodes[node_ref.idx as usize].expires_at = 0;
+    nodes[node_ref.idx as usize].version = 0;
+    nodes[node_ref.idx as usize].negative = false;
     nodes[node_ref.idx as usize].freq = 0;
     nodes[node_ref.idx as usize].next = node_ref.idx;
     nodes[node_ref.idx as usize].prev = node_ref.idx;
@@ -607,7 +1894,7 @@ fn occupy_node(
 
 // Get NodeRef<Q: QueueWithMembers, Occupied> given index. Does not check if Node is actually in the state that NodeRef assumes.
 // Panics if the node is not part of any queue.
-fn get_node_ref<Q: QueueWithMembers>(idx: usize, nodes: &Vec<Node>) -> NodeRef<Q, Occupied> {
+fn get_node_ref<Q: QueueWithMembers>(idx: usize, nodes: &[Node]) -> NodeRef<Q, Occupied> {
     match nodes[idx].queue {
         QueueTypeId::NoQueue => panic!("Node at index {} is not part of any queue", idx),
         _ => NodeRef {
@@ -617,3 +1904,10 @@ fn get_node_ref<Q: QueueWithMembers>(idx: usize, nodes: &Vec<Node>) -> NodeRef<Q
         },
     }
 }
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
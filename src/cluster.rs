@@ -0,0 +1,324 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bincode::config::{Configuration, standard};
+use foca::{
+    AccumulatingRuntime, BincodeCodec, Config, Foca, NoCustomBroadcast, OwnedNotification, Timer,
+};
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep_until};
+
+// Cluster membership with the SWIM protocol (implemented by foca): members probe each other
+// over UDP, suspect members that don't answer and declare them down if they stay silent,
+// and spread membership updates on top of the probes.
+//
+// A single task owns the foca instance and the socket. It feeds foca with received
+// packets, due timers and commands, then sends the packets foca produced, schedules its
+// timers and publishes membership changes.
+
+type Membership = Foca<NodeId, BincodeCodec<Configuration>, SmallRng, NoCustomBroadcast>;
+
+// Membership events kept for subscribers that fall behind
+const EVENTS_CAPACITY: usize = 256;
+
+/// Identity of a cache node in the cluster
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NodeId {
+    /// Address other members send cluster messages to
+    pub addr: SocketAddr,
+    /// Tells restarts of a node on the same address apart, the highest one wins. Starts at
+    /// the start time in milliseconds, and grows when the node rejoins after it was
+    /// declared down.
+    pub version: u64,
+}
+
+impl NodeId {
+    pub fn new(addr: SocketAddr) -> Self {
+        let version = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        NodeId { addr, version }
+    }
+}
+
+impl foca::Identity for NodeId {
+    type Addr = SocketAddr;
+
+    fn renew(&self) -> Option<Self> {
+        Some(NodeId {
+            addr: self.addr,
+            version: self.version.wrapping_add(1),
+        })
+    }
+
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn win_addr_conflict(&self, adversary: &Self) -> bool {
+        self.version > adversary.version
+    }
+}
+
+/// Configuration of `Cluster::start`
+#[derive(Debug, Clone)]
+pub struct ClusterOptions {
+    /// Address of the UDP socket cluster messages are received on
+    pub bind_addr: SocketAddr,
+    /// Identity announced to other members. By default the address of the socket, so it
+    /// has to be set when binding to an unspecified address such as 0.0.0.0.
+    pub identity: Option<NodeId>,
+    /// Addresses of members to join the cluster through. A node without seeds waits
+    /// for others to join it.
+    pub seeds: Vec<SocketAddr>,
+    /// Expected number of nodes, used to tune how long updates are spread
+    pub cluster_size: NonZeroU32,
+}
+
+impl ClusterOptions {
+    pub fn new(bind_addr: SocketAddr, seeds: Vec<SocketAddr>) -> Self {
+        ClusterOptions {
+            bind_addr,
+            identity: None,
+            seeds,
+            cluster_size: NonZeroU32::new(16).unwrap(),
+        }
+    }
+}
+
+/// Change of the membership, see `Cluster::subscribe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipEvent {
+    /// Member joined, or came back after it was declared down
+    Up(NodeId),
+    /// Member left, or stopped answering and was declared down
+    Down(NodeId),
+    /// Member rejoined with a new identity (it restarted on the same address)
+    Renamed { old: NodeId, new: NodeId },
+    /// This node was declared down by others, and rejoined with a new identity
+    Rejoined(NodeId),
+}
+
+#[derive(Debug)]
+pub enum ClusterError {
+    Io(io::Error),
+    /// Foca rejected an operation
+    Protocol(foca::Error),
+    /// Membership task is not running anymore
+    Stopped,
+}
+
+enum Command {
+    Announce(SocketAddr),
+    Leave(oneshot::Sender<Result<(), foca::Error>>),
+}
+
+// Shared with the membership task, updated after every membership change
+struct ClusterState {
+    identity: NodeId,
+    members: Vec<NodeId>,
+}
+
+/// Membership of this node in a cluster of cache nodes. Dropping it stops the membership
+/// task without telling the others, which declare the node down after a while; `leave`
+/// tells them right away.
+pub struct Cluster {
+    state: Arc<Mutex<ClusterState>>,
+    events: broadcast::Sender<MembershipEvent>,
+    commands: mpsc::UnboundedSender<Command>,
+    handle: JoinHandle<()>,
+    local_addr: SocketAddr,
+}
+
+impl Cluster {
+    /// Binds the socket, starts the membership task and announces the node to the seeds.
+    /// Must be called from within a tokio runtime.
+    pub async fn start(options: ClusterOptions) -> Result<Self, ClusterError> {
+        let socket = UdpSocket::bind(options.bind_addr)
+            .await
+            .map_err(ClusterError::Io)?;
+        let local_addr = socket.local_addr().map_err(ClusterError::Io)?;
+        let identity = options.identity.unwrap_or_else(|| NodeId::new(local_addr));
+        let config = Config::new_lan(options.cluster_size);
+        let packet_size = config.max_packet_size.get();
+        let foca = Foca::new(
+            identity.clone(),
+            config,
+            SmallRng::from_os_rng(),
+            BincodeCodec(standard()),
+        );
+
+        let state = Arc::new(Mutex::new(ClusterState {
+            identity,
+            members: Vec::new(),
+        }));
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let (commands, receiver) = mpsc::unbounded_channel();
+        for seed in options.seeds {
+            // can't fail, the receiver is not dropped yet
+            let _ = commands.send(Command::Announce(seed));
+        }
+        let task = MembershipTask {
+            foca,
+            socket,
+            runtime: AccumulatingRuntime::new(),
+            timers: BinaryHeap::new(),
+            state: Arc::clone(&state),
+            events: events.clone(),
+        };
+        let handle = tokio::spawn(task.run(receiver, packet_size));
+        Ok(Cluster {
+            state,
+            events,
+            commands,
+            handle,
+            local_addr,
+        })
+    }
+
+    /// Current identity of this node
+    pub fn identity(&self) -> NodeId {
+        self.lock_state().identity.clone()
+    }
+
+    /// Address of the socket, useful when binding to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Other members that are alive (or suspected, but not declared down yet)
+    pub fn members(&self) -> Vec<NodeId> {
+        self.lock_state().members.clone()
+    }
+
+    /// Receives membership changes from now on. A receiver that falls more than a few
+    /// hundred events behind loses the oldest ones, and should read `members` again.
+    pub fn subscribe(&self) -> broadcast::Receiver<MembershipEvent> {
+        self.events.subscribe()
+    }
+
+    /// Joins the cluster through another member, in addition to the seeds
+    pub fn announce(&self, addr: SocketAddr) -> Result<(), ClusterError> {
+        self.commands
+            .send(Command::Announce(addr))
+            .map_err(|_| ClusterError::Stopped)
+    }
+
+    /// Tells the other members that this node leaves, and stops the membership task
+    pub async fn leave(self) -> Result<(), ClusterError> {
+        let (done, result) = oneshot::channel();
+        self.commands
+            .send(Command::Leave(done))
+            .map_err(|_| ClusterError::Stopped)?;
+        let left = result.await.map_err(|_| ClusterError::Stopped)?;
+        let _ = self.handle.await;
+        left.map_err(ClusterError::Protocol)
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, ClusterState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+struct MembershipTask {
+    foca: Membership,
+    socket: UdpSocket,
+    runtime: AccumulatingRuntime<NodeId>,
+    // due timers of foca, the earliest first
+    timers: BinaryHeap<Reverse<(Instant, Timer<NodeId>)>>,
+    state: Arc<Mutex<ClusterState>>,
+    events: broadcast::Sender<MembershipEvent>,
+}
+
+impl MembershipTask {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>, packet_size: usize) {
+        let mut packet = vec![0u8; packet_size];
+        loop {
+            let next_timer = self.timers.peek().map(|Reverse((at, _))| *at);
+            // foca tolerates lost, duplicated and malformed packets, so errors of single
+            // inputs are ignored
+            let _ = tokio::select! {
+                received = self.socket.recv_from(&mut packet) => match received {
+                    Ok((len, _)) => self.foca.handle_data(&packet[..len], &mut self.runtime),
+                    // e.g. an ICMP error for a packet sent to a member that is gone
+                    Err(_) => continue,
+                },
+                _ = sleep_until(next_timer.unwrap_or_else(Instant::now)), if next_timer.is_some() => {
+                    let Some(Reverse((_, timer))) = self.timers.pop() else {
+                        continue;
+                    };
+                    self.foca.handle_timer(timer, &mut self.runtime)
+                }
+                command = commands.recv() => match command {
+                    Some(Command::Announce(addr)) => {
+                        // only the address of the destination matters for an announce
+                        let seed = NodeId { addr, version: 0 };
+                        self.foca.announce(seed, &mut self.runtime)
+                    }
+                    Some(Command::Leave(done)) => {
+                        let left = self.foca.leave_cluster(&mut self.runtime);
+                        self.process_output().await;
+                        let _ = done.send(left);
+                        return;
+                    }
+                    None => return,
+                },
+            };
+            self.process_output().await;
+        }
+    }
+
+    // Sends the packets, schedules the timers and publishes the changes foca produced
+    async fn process_output(&mut self) {
+        while let Some((member, data)) = self.runtime.to_send() {
+            // delivery is not guaranteed anyway, foca retries what matters
+            let _ = self.socket.send_to(&data, member.addr).await;
+        }
+        let now = Instant::now();
+        while let Some((delay, timer)) = self.runtime.to_schedule() {
+            self.timers.push(Reverse((now + delay, timer)));
+        }
+
+        let mut events = Vec::new();
+        while let Some(notification) = self.runtime.to_notify() {
+            match notification {
+                OwnedNotification::MemberUp(member) => events.push(MembershipEvent::Up(member)),
+                OwnedNotification::MemberDown(member) => events.push(MembershipEvent::Down(member)),
+                OwnedNotification::Rename(old, new) => {
+                    events.push(MembershipEvent::Renamed { old, new })
+                }
+                OwnedNotification::Rejoin(identity) => {
+                    events.push(MembershipEvent::Rejoined(identity))
+                }
+                _ => {}
+            }
+        }
+        if events.is_empty() {
+            return;
+        }
+        // state is updated first, so subscribers see it when they get the events
+        {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.identity = self.foca.identity().clone();
+            state.members = self
+                .foca
+                .iter_members()
+                .map(|member| member.id().clone())
+                .collect();
+        }
+        for event in events {
+            // fails only if nobody is subscribed
+            let _ = self.events.send(event);
+        }
+    }
+}
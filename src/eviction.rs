@@ -0,0 +1,120 @@
+use std::fmt::Debug;
+
+use crate::sync::{GHOST_THRESHOLD_RATIO, MAIN_THRESHOLD_RATIO, SMALL_THRESHOLD_RATIO};
+
+/// Shares of the capacity of a shard given to each queue
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueRatios {
+    /// New entries are inserted into the small queue, or into the main queue if this is 0
+    pub small: f64,
+    pub main: f64,
+    /// Ghost queue keeps keys of entries evicted from the small queue
+    pub ghost: f64,
+}
+
+/// Decides how entries move through the queues of a shard, and which ones are evicted.
+/// Shards keep the queues (small, main, ghost and pinned), pinned entries, quotas and costs
+/// work the same way with every policy.
+pub trait EvictionPolicy: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn queue_ratios(&self) -> QueueRatios;
+
+    /// Frequency of an entry after it is read or updated, shards cap it at
+    /// `Options::max_frequency`
+    fn on_access(&self, freq: u8) -> u8;
+
+    /// Whether a read or updated entry of the main queue is moved to its tail (newest end)
+    fn moves_to_tail(&self) -> bool {
+        false
+    }
+
+    /// Frequency the oldest entry of the main queue is reinserted with, or `None` to evict it
+    fn second_chance(&self, freq: u8) -> Option<u8>;
+}
+
+/// S3-FIFO: new entries go through a small FIFO queue, entries that were accessed there
+/// are promoted to the main queue, others leave a ghost behind. Main queue is a FIFO
+/// where accessed entries are reinserted. The default policy.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct S3Fifo;
+
+impl EvictionPolicy for S3Fifo {
+    fn name(&self) -> &'static str {
+        "s3-fifo"
+    }
+
+    fn queue_ratios(&self) -> QueueRatios {
+        QueueRatios {
+            small: SMALL_THRESHOLD_RATIO,
+            main: MAIN_THRESHOLD_RATIO,
+            ghost: GHOST_THRESHOLD_RATIO,
+        }
+    }
+
+    fn on_access(&self, freq: u8) -> u8 {
+        freq.saturating_add(1)
+    }
+
+    fn second_chance(&self, freq: u8) -> Option<u8> {
+        freq.checked_sub(1)
+    }
+}
+
+/// Least recently used: accessed entries are moved to the tail of the main queue,
+/// and the oldest one is evicted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn name(&self) -> &'static str {
+        "lru"
+    }
+
+    fn queue_ratios(&self) -> QueueRatios {
+        QueueRatios {
+            small: 0.0,
+            main: 1.0,
+            ghost: 0.0,
+        }
+    }
+
+    fn on_access(&self, freq: u8) -> u8 {
+        freq
+    }
+
+    fn moves_to_tail(&self) -> bool {
+        true
+    }
+
+    fn second_chance(&self, _freq: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// CLOCK: accessed entries get a reference bit, the oldest entry of the main queue is
+/// reinserted with the bit cleared if it was set, and evicted otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Clock;
+
+impl EvictionPolicy for Clock {
+    fn name(&self) -> &'static str {
+        "clock"
+    }
+
+    fn queue_ratios(&self) -> QueueRatios {
+        QueueRatios {
+            small: 0.0,
+            main: 1.0,
+            ghost: 0.0,
+        }
+    }
+
+    fn on_access(&self, _freq: u8) -> u8 {
+        1
+    }
+
+    fn second_chance(&self, freq: u8) -> Option<u8> {
+        (freq > 0).then_some(0)
+    }
+}
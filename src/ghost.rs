@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+use std::hash::Hash;
+
+use hashbrown::HashTable;
+
+// Ghost queue of S3-FIFO: remembers entries recently evicted from the small queue, so they
+// go straight to the main queue if they are inserted again. Only the hash of the key
+// (fingerprint) and the weight of the entry are kept, a ghost costs about 25 bytes whatever
+// the size of its key. Distinct keys with the same 64-bit hash share a ghost, which only
+// means one of them may be admitted to the main queue without deserving it.
+//
+// Removing a ghost from the middle of the queue leaves its fingerprint in `order`,
+// stale fingerprints are skipped when popped, and dropped once they outnumber live ones.
+//
+// Fingerprints are computed with fixed seeds, unlike hashes of the shard map, so a key has
+// the same fingerprint in every cache instance and process, and ghosts survive snapshots.
+// `AlsoCache` routes keys to shards by fingerprint for the same reason.
+
+const FINGERPRINTS: ahash::RandomState = ahash::RandomState::with_seeds(
+    0x9e37_79b9_7f4a_7c15,
+    0xbf58_476d_1ce4_e5b9,
+    0x94d0_49bb_1331_11eb,
+    0x2545_f491_4f6c_dd1d,
+);
+
+/// Fingerprint of the key, the same in every process
+#[inline(always)]
+pub(crate) fn fingerprint<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    FINGERPRINTS.hash_one(key)
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct GhostQueue {
+    // fingerprints from the oldest to the newest, including stale ones
+    order: VecDeque<u64>,
+    // live ghosts: fingerprint and weight, the fingerprint is used as the hash
+    ghosts: HashTable<(u64, u64)>,
+    // total weight of live ghosts
+    size: u64,
+}
+
+impl GhostQueue {
+    pub(crate) fn push(&mut self, fingerprint: u64, weight: u64) {
+        // a key evicted again while it still has a ghost is remembered once, with the newest
+        // weight. Its older fingerprint in `order` makes it leave the queue a bit earlier.
+        self.remove(fingerprint);
+        self.ghosts
+            .insert_unique(fingerprint, (fingerprint, weight), |&(fingerprint, _)| {
+                fingerprint
+            });
+        self.order.push_back(fingerprint);
+        self.size += weight;
+    }
+
+    /// Removes the ghost, returns its weight if it was there
+    pub(crate) fn remove(&mut self, fingerprint: u64) -> Option<u64> {
+        let entry = self
+            .ghosts
+            .find_entry(fingerprint, |&(other, _)| other == fingerprint)
+            .ok()?;
+        let ((_, weight), _) = entry.remove();
+        self.size = self.size.saturating_sub(weight);
+        if self.order.len() > 2 * self.ghosts.len() + 64 {
+            let ghosts = &self.ghosts;
+            self.order.retain(|&fingerprint| {
+                ghosts
+                    .find(fingerprint, |&(other, _)| other == fingerprint)
+                    .is_some()
+            });
+        }
+        Some(weight)
+    }
+
+    /// Forgets the oldest ghost, returns its weight or None if the queue is empty
+    pub(crate) fn pop_oldest(&mut self) -> Option<u64> {
+        while let Some(fingerprint) = self.order.pop_front() {
+            if let Ok(entry) = self
+                .ghosts
+                .find_entry(fingerprint, |&(other, _)| other == fingerprint)
+            {
+                let ((_, weight), _) = entry.remove();
+                self.size = self.size.saturating_sub(weight);
+                return Some(weight);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ghosts.len()
+    }
+
+    /// Live ghosts (fingerprint and weight) from the oldest to the newest, in the order they
+    /// would be popped
+    pub(crate) fn export(&self) -> Vec<(u64, u64)> {
+        let mut seen = HashTable::with_capacity(self.ghosts.len());
+        let mut ghosts = Vec::with_capacity(self.ghosts.len());
+        for &fingerprint in &self.order {
+            let Some(&ghost) = self
+                .ghosts
+                .find(fingerprint, |&(other, _)| other == fingerprint)
+            else {
+                continue;
+            };
+            // only the oldest fingerprint of a ghost counts, like in `pop_oldest`
+            if seen
+                .find(fingerprint, |&other| other == fingerprint)
+                .is_none()
+            {
+                seen.insert_unique(fingerprint, fingerprint, |&other| other);
+                ghosts.push(ghost);
+            }
+        }
+        ghosts
+    }
+
+    /// Total weight of live ghosts counted again, `size` should be equal to it
+    pub(crate) fn recount_size(&self) -> u64 {
+        self.ghosts.iter().map(|&(_, weight)| weight).sum()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.order.clear();
+        self.ghosts.clear();
+        self.size = 0;
+    }
+}
@@ -0,0 +1,165 @@
+use std::cmp::Reverse;
+
+use hashbrown::HashTable;
+
+// Space-saving heavy hitters: counts accesses of at most `slots` keys (by fingerprint).
+// A key that is not counted yet replaces the least counted one and inherits its count,
+// so counts never underestimate, and overestimate by at most the inherited count (error).
+// Any key accessed more than 1/slots of the time is guaranteed to be counted.
+//
+// Counters are kept in a min-heap by count, so the least counted key is found in O(1)
+// and an access costs O(log slots). Counts are halved after `AGING_PERIOD * slots`
+// accesses, so keys that were hot a while ago make way for the current ones.
+
+const AGING_PERIOD: u64 = 64;
+
+/// Frequently accessed key, see `AlsoCache::hot_keys`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotKey<Key> {
+    pub key: Key,
+    /// Name of the namespace of the key, "" for the default one
+    pub namespace: String,
+    /// Index of the shard holding the key
+    pub shard: usize,
+    /// Approximate number of recent accesses, never less than the actual one
+    pub count: u64,
+    /// `count` exceeds the actual number of accesses by at most this much
+    pub error: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Counter {
+    fingerprint: u64,
+    count: u64,
+    error: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct HeavyHitters {
+    slots: usize,
+    // min-heap by count
+    heap: Vec<Counter>,
+    // fingerprint and position of its counter in the heap, the fingerprint is used as the hash
+    positions: HashTable<(u64, usize)>,
+    accesses: u64,
+}
+
+impl HeavyHitters {
+    pub(crate) fn new(slots: usize) -> Self {
+        HeavyHitters {
+            slots,
+            heap: Vec::with_capacity(slots),
+            positions: HashTable::with_capacity(slots),
+            accesses: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, fingerprint: u64) {
+        if let Some(&(_, pos)) = self
+            .positions
+            .find(fingerprint, |&(other, _)| other == fingerprint)
+        {
+            self.heap[pos].count += 1;
+            self.sift_down(pos);
+        } else if self.heap.len() < self.slots {
+            let pos = self.heap.len();
+            self.heap.push(Counter {
+                fingerprint,
+                count: 1,
+                error: 0,
+            });
+            self.positions
+                .insert_unique(fingerprint, (fingerprint, pos), |&(other, _)| other);
+            self.sift_up(pos);
+        } else {
+            // the new key may have been accessed as often as the key it replaces
+            let Counter {
+                fingerprint: replaced,
+                count,
+                ..
+            } = self.heap[0];
+            if let Ok(entry) = self
+                .positions
+                .find_entry(replaced, |&(other, _)| other == replaced)
+            {
+                entry.remove();
+            }
+            self.heap[0] = Counter {
+                fingerprint,
+                count: count + 1,
+                error: count,
+            };
+            self.positions
+                .insert_unique(fingerprint, (fingerprint, 0), |&(other, _)| other);
+            self.sift_down(0);
+        }
+
+        self.accesses += 1;
+        if self.accesses >= AGING_PERIOD * self.slots as u64 {
+            // halving keeps the order of counts, so the heap stays valid
+            for counter in &mut self.heap {
+                counter.count /= 2;
+                counter.error /= 2;
+            }
+            self.accesses = 0;
+        }
+    }
+
+    /// Counted fingerprints with their counts and errors, the most accessed first
+    pub(crate) fn top(&self) -> Vec<(u64, u64, u64)> {
+        let mut top: Vec<_> = self
+            .heap
+            .iter()
+            .filter(|counter| counter.count > 0)
+            .map(|counter| (counter.fingerprint, counter.count, counter.error))
+            .collect();
+        top.sort_unstable_by_key(|&(_, count, _)| Reverse(count));
+        top
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.heap.clear();
+        self.positions.clear();
+        self.accesses = 0;
+    }
+
+    fn sift_up(&mut self, mut pos: usize) {
+        while pos > 0 {
+            let parent = (pos - 1) / 2;
+            if self.heap[parent].count <= self.heap[pos].count {
+                break;
+            }
+            self.swap(pos, parent);
+            pos = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut pos: usize) {
+        loop {
+            let mut smallest = pos;
+            for child in [2 * pos + 1, 2 * pos + 2] {
+                if child < self.heap.len() && self.heap[child].count < self.heap[smallest].count {
+                    smallest = child;
+                }
+            }
+            if smallest == pos {
+                break;
+            }
+            self.swap(pos, smallest);
+            pos = smallest;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        for pos in [a, b] {
+            let fingerprint = self.heap[pos].fingerprint;
+            if let Some(entry) = self
+                .positions
+                .find_mut(fingerprint, |&(other, _)| other == fingerprint)
+            {
+                entry.1 = pos;
+            }
+        }
+    }
+}
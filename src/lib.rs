@@ -1,15 +1,47 @@
 pub mod cache_shard;
+pub mod cluster;
+pub mod eviction;
+mod ghost;
+pub mod hot_keys;
+pub mod namespace;
+pub mod oplog;
+pub mod options;
+mod sketch;
+pub mod snapshot;
 pub mod sync;
+mod tag_index;
+pub mod trace;
 
+pub use cache_shard::{
+    QueueEntryInfo, QueueInfo, ShardError, ShardInfo, ShardProblem, ShardReport,
+};
+pub use cluster::{Cluster, ClusterError, ClusterOptions, MembershipEvent, NodeId};
+pub use eviction::{Clock, EvictionPolicy, Lru, QueueRatios, S3Fifo};
+pub use hot_keys::HotKey;
+pub use namespace::{Namespace, NamespaceId, NamespaceQuota, NamespaceStats, namespace_id};
+pub use oplog::{FsyncPolicy, OpLogOptions};
+pub use options::Options;
+pub use snapshot::{SnapshotError, SnapshotSchedule, SnapshotTask};
 pub use sync::{AlsoCache, DefaultWeighter, GetCacheError, InsertCacheError, Weighter};
+pub use trace::{Trace, TraceOp, TraceOptions, TraceRecord, read_trace};
 
 #[cfg(test)]
 mod tests {
     use serde_derive::{Deserialize, Serialize};
 
-    use crate::{GetCacheError, sync::AlsoCache};
+    use ahash::RandomState;
+
+    use std::sync::Arc;
+
+    use crate::{
+        Clock, Cluster, ClusterOptions, DefaultWeighter, EvictionPolicy, FsyncPolicy,
+        GetCacheError, InsertCacheError, Lru, MembershipEvent, NamespaceQuota, NamespaceStats,
+        OpLogOptions, Options, ShardError, SnapshotError, SnapshotSchedule, TraceOp, TraceOptions,
+        namespace_id, read_trace, sync::AlsoCache,
+    };
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_insert_get_delete() {
         let cache = AlsoCache::default(2000); // size in bytes
 
@@ -64,7 +96,7 @@ mod tests {
             "Get after delete should fail for struct value"
         );
 
-        cache.print_queues(5);
+        println!("{:?}", cache.inspect(5));
     }
 
     #[test]
@@ -82,7 +114,7 @@ mod tests {
             }
         }
 
-        cache.print_queues(10);
+        println!("{:?}", cache.inspect(10));
 
         let mut found_count = 0;
         for i in 0..10000 {
@@ -112,6 +144,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_many_deletes() {
         let cache = AlsoCache::default(2000); // size in bytes
 
@@ -131,7 +164,7 @@ mod tests {
             }
         }
 
-        cache.print_queues(10);
+        println!("{:?}", cache.inspect(10));
 
         // delete every other item
         let mut deleted_count = 0;
@@ -191,10 +224,11 @@ mod tests {
             assert_eq!(delete_result, false, "Double deletion should return false");
         }
 
-        cache.print_queues(10);
+        println!("{:?}", cache.inspect(10));
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn test_sharded_cache() {
         let cache = AlsoCache::default(20_000);
 
@@ -250,6 +284,1342 @@ mod tests {
         println!("Basic sharded cache test completed successfully");
     }
 
+    #[test]
+    fn test_poisoned_shard_is_reset() {
+        use serde::de::{Deserialize, Deserializer};
+
+        // deserializing this type panics while the shard lock is held
+        #[derive(Debug)]
+        struct Panicky;
+        impl<'de> Deserialize<'de> for Panicky {
+            fn deserialize<D: Deserializer<'de>>(_: D) -> Result<Self, D::Error> {
+                panic!("deserialization panic");
+            }
+        }
+
+        let cache = AlsoCache::default(2000);
+        for i in 0..1000 {
+            cache
+                .insert(i.to_string(), &i)
+                .expect("insert should succeed");
+        }
+        assert!(cache.get_eviction_count() > 0);
+        let key = "poison".to_string();
+        cache
+            .insert(key.clone(), &1u32)
+            .expect("insert should succeed");
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _ = cache.get::<Panicky>(&key);
+        }));
+        assert!(res.is_err(), "Get should have panicked");
+
+        // first access after the panic resets the shard and reports it
+        let retrieved: Result<u32, GetCacheError> = cache.get(&key);
+        assert!(
+            matches!(
+                retrieved,
+                Err(GetCacheError::ShardReset(ShardError::Poisoned))
+            ),
+            "Poisoned shard should be reset"
+        );
+        // nothing of the old state is reported anymore
+        assert_eq!(cache.get_eviction_count(), 0);
+        assert!(cache.validate().iter().all(|report| report.is_ok()));
+
+        // afterwards the shard works as usual
+        cache
+            .insert(key.clone(), &2u32)
+            .expect("insert should succeed");
+        assert_eq!(cache.get::<u32>(&key).expect("get should succeed"), 2);
+
+        // a snapshot resets a poisoned shard too, instead of saving what the panic left
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _ = cache.get::<Panicky>(&key);
+        }));
+        assert!(res.is_err(), "Get should have panicked");
+        let path =
+            std::env::temp_dir().join(format!("also_cache_poisoned_{}.bin", std::process::id()));
+        cache.save_snapshot(&path).expect("save should succeed");
+        let restored = AlsoCache::<String, _, _>::default(2000);
+        let count = restored.load_snapshot(&path).expect("load should succeed");
+        std::fs::remove_file(&path).expect("snapshot file should be removed");
+        assert_eq!(count, 0);
+        assert!(cache.get::<u32>(&key).is_err());
+    }
+
+    #[test]
+    fn test_update_with_lighter_value() {
+        let cache = AlsoCache::default(2000);
+        let key = "shrinking".to_string();
+
+        cache
+            .insert(key.clone(), &"a much longer value".to_string())
+            .expect("insert should succeed");
+        cache
+            .insert(key.clone(), &"short".to_string())
+            .expect("update with a lighter value should succeed");
+
+        assert_eq!(
+            cache.get::<String>(&key).expect("get should succeed"),
+            "short"
+        );
+    }
+
+    #[test]
+    fn test_oversized_entries() {
+        let options = Options {
+            max_entry_weight: Some(1000),
+            large_entries_bypass_small: true,
+            ..Options::default()
+        };
+        let cache = AlsoCache::with_options(0, 2000, DefaultWeighter, RandomState::new(), options);
+
+        // entries over the limit are rejected
+        let res = cache.insert("too_large".to_string(), &vec![0u8; 1500]);
+        assert!(
+            matches!(res, Err(InsertCacheError::TooLarge)),
+            "Entry over max weight should be rejected"
+        );
+
+        // fill small queue with a few light entries
+        for i in 0..10 {
+            cache
+                .insert(format!("small_{}", i), &i)
+                .expect("insert should succeed");
+        }
+
+        // large entry (heavier than small queue threshold) should not flush the small queue
+        let blob = vec![1u8; 500];
+        cache
+            .insert("blob".to_string(), &blob)
+            .expect("insert should succeed");
+        let retrieved: Vec<u8> = cache.get(&"blob".to_string()).expect("get should succeed");
+        assert_eq!(retrieved, blob);
+        for i in 0..10 {
+            let retrieved: i32 = cache
+                .get(&format!("small_{}", i))
+                .expect("small entries should survive a large insert");
+            assert_eq!(retrieved, i);
+        }
+    }
+
+    #[test]
+    fn test_pinned_entries() {
+        let cache = AlsoCache::default(2000);
+
+        cache
+            .insert_pinned("config".to_string(), &"feature flags".to_string())
+            .expect("insert pinned should succeed");
+        cache
+            .insert("pin_later".to_string(), &"other value".to_string())
+            .expect("insert should succeed");
+        assert!(cache.pin(&"pin_later".to_string()), "Pin should succeed");
+        assert!(
+            !cache.pin(&"missing".to_string()),
+            "Pinning missing key should fail"
+        );
+
+        // flush the cache with lots of entries that are never read
+        for i in 0..5000 {
+            cache
+                .insert(format!("key_{}", i), &i)
+                .expect("insert should succeed");
+        }
+
+        let config: String = cache
+            .get(&"config".to_string())
+            .expect("pinned entry should survive eviction");
+        assert_eq!(config, "feature flags");
+        let other: String = cache
+            .get(&"pin_later".to_string())
+            .expect("pinned entry should survive eviction");
+        assert_eq!(other, "other value");
+
+        // after unpinning, entry is evicted as usual
+        assert!(cache.unpin(&"config".to_string()), "Unpin should succeed");
+        assert!(
+            !cache.unpin(&"config".to_string()),
+            "Double unpin should fail"
+        );
+        // (flood entries are read once, so they are promoted and push it out of main queue)
+        for i in 0..5000 {
+            cache
+                .insert(format!("key_{}", i), &i)
+                .expect("insert should succeed");
+            let _: Result<i32, GetCacheError> = cache.get(&format!("key_{}", i));
+        }
+        let res: Result<String, GetCacheError> = cache.get(&"config".to_string());
+        assert!(
+            matches!(res, Err(GetCacheError::KeyNotFound)),
+            "Unpinned entry should be evicted eventually"
+        );
+
+        // deleting a pinned entry works as for any other entry
+        assert!(
+            cache.delete(&"pin_later".to_string()),
+            "Delete should succeed"
+        );
+    }
+
+    #[test]
+    fn test_cost_aware_eviction() {
+        let cache = AlsoCache::default(2000); // single shard, small queue fits 200 bytes
+        let value = |c: char| c.to_string().repeat(50); // 51 bytes when encoded
+
+        cache
+            .insert_with_cost("expensive".to_string(), &value('e'), 1000)
+            .expect("insert should succeed");
+        cache
+            .insert_with_cost("cheap".to_string(), &value('c'), 1)
+            .expect("insert should succeed");
+        cache
+            .insert_with_cost("filler_1".to_string(), &value('1'), 500)
+            .expect("insert should succeed");
+
+        // small queue overflows, both oldest entries were never read, so the cheaper one goes
+        cache
+            .insert_with_cost("filler_2".to_string(), &value('2'), 500)
+            .expect("insert should succeed");
+
+        let expensive: String = cache
+            .get(&"expensive".to_string())
+            .expect("expensive entry should be kept");
+        assert_eq!(expensive, value('e'));
+        let cheap: Result<String, GetCacheError> = cache.get(&"cheap".to_string());
+        assert!(
+            matches!(cheap, Err(GetCacheError::KeyNotFound)),
+            "Cheap entry should be evicted first"
+        );
+    }
+
+    #[test]
+    fn test_negative_caching() {
+        use std::{cell::Cell, time::Duration};
+
+        let cache = AlsoCache::default(2000);
+        let key = "missing".to_string();
+
+        cache
+            .insert_absent(key.clone(), Duration::from_millis(50))
+            .expect("insert absent should succeed");
+        let res: Result<String, GetCacheError> = cache.get(&key);
+        assert!(
+            matches!(res, Err(GetCacheError::Negative)),
+            "Cached miss should be reported as negative"
+        );
+
+        // after ttl passes, negative entry is gone
+        std::thread::sleep(Duration::from_millis(60));
+        let res: Result<String, GetCacheError> = cache.get(&key);
+        assert!(
+            matches!(res, Err(GetCacheError::KeyNotFound)),
+            "Expired negative entry should not be found"
+        );
+
+        // loader is called only once for a missing key
+        let calls = Cell::new(0);
+        let loader = |_: &String| -> Option<String> {
+            calls.set(calls.get() + 1);
+            None
+        };
+        for _ in 0..3 {
+            let res = cache
+                .get_or_load(&key, Duration::from_secs(60), loader)
+                .expect("get or load should succeed");
+            assert_eq!(res, None);
+        }
+        assert_eq!(calls.get(), 1, "Loader should be called once");
+
+        // real value replaces the negative entry
+        cache
+            .insert(key.clone(), &"found".to_string())
+            .expect("insert should succeed");
+        let res = cache
+            .get_or_load(&key, Duration::from_secs(60), loader)
+            .expect("get or load should succeed");
+        assert_eq!(res.as_deref(), Some("found"));
+        assert_eq!(
+            calls.get(),
+            1,
+            "Loader should not be called for cached value"
+        );
+    }
+
+    #[test]
+    fn test_compare_and_set() {
+        let cache = AlsoCache::default(2000);
+        let key = "aggregate".to_string();
+        cache
+            .insert(key.clone(), &10u64)
+            .expect("insert should succeed");
+
+        // two workers read the same version
+        let (val_a, version_a): (u64, u64) = cache
+            .get_with_version(&key)
+            .expect("get with version should succeed");
+        let (val_b, version_b): (u64, u64) = cache
+            .get_with_version(&key)
+            .expect("get with version should succeed");
+        assert_eq!(version_a, version_b);
+
+        // first update wins
+        let new_version = cache
+            .compare_and_set(key.clone(), version_a, &(val_a + 1))
+            .expect("first compare and set should succeed");
+        assert!(new_version > version_a, "Version should grow");
+
+        // second one sees that value was changed
+        let res = cache.compare_and_set(key.clone(), version_b, &(val_b + 1));
+        assert!(
+            matches!(res, Err(InsertCacheError::VersionMismatch)),
+            "Stale compare and set should fail"
+        );
+        let (val, version): (u64, u64) = cache
+            .get_with_version(&key)
+            .expect("get with version should succeed");
+        assert_eq!((val, version), (11, new_version));
+
+        // plain insert changes version as well
+        cache
+            .insert(key.clone(), &0u64)
+            .expect("insert should succeed");
+        let res = cache.compare_and_set(key.clone(), new_version, &1u64);
+        assert!(matches!(res, Err(InsertCacheError::VersionMismatch)));
+
+        // missing key can't be compared
+        let res = cache.compare_and_set("missing".to_string(), 1, &1u64);
+        assert!(matches!(res, Err(InsertCacheError::VersionMismatch)));
+    }
+
+    #[test]
+    fn test_counters() {
+        use std::{sync::Arc, time::Duration};
+
+        let cache = Arc::new(AlsoCache::default(20_000));
+        let key = "requests".to_string();
+
+        // concurrent increments are not lost
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                let key = key.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..500 {
+                        cache.incr(key.clone(), 1).expect("incr should succeed");
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().expect("thread should not panic");
+        }
+        assert_eq!(cache.get::<i64>(&key).expect("get should succeed"), 2000);
+        assert_eq!(
+            cache.decr(key.clone(), 500).expect("decr should succeed"),
+            1500
+        );
+
+        // counter with ttl is reset when window passes, and is not extended by increments
+        let limited = "rate_limit".to_string();
+        let ttl = Duration::from_millis(200);
+        assert_eq!(cache.incr_with_ttl(limited.clone(), 1, ttl).unwrap(), 1);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(cache.incr_with_ttl(limited.clone(), 1, ttl).unwrap(), 2);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(cache.incr_with_ttl(limited.clone(), 1, ttl).unwrap(), 1);
+
+        // compare-and-set keeps the window too
+        let (_, version): (i64, u64) = cache
+            .get_with_version(&limited)
+            .expect("get should succeed");
+        cache
+            .compare_and_set(limited.clone(), version, &5i64)
+            .expect("compare and set should succeed");
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(cache.get::<i64>(&limited).is_err());
+
+        // a cached miss does not lend its ttl to a new counter
+        let missing = "missing".to_string();
+        cache
+            .insert_absent(missing.clone(), Duration::from_millis(50))
+            .expect("insert absent should succeed");
+        assert_eq!(cache.incr_with_ttl(missing.clone(), 1, ttl).unwrap(), 1);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get::<i64>(&missing).ok(), Some(1));
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let path =
+            std::env::temp_dir().join(format!("also_cache_snapshot_{}.bin", std::process::id()));
+        // same hasher seeds, so keys land in the same shards after restore
+        let new_cache =
+            || AlsoCache::with(20_000, DefaultWeighter, RandomState::with_seeds(1, 2, 3, 4));
+        let cache = new_cache();
+
+        for i in 0..1000 {
+            cache
+                .insert(format!("key_{}", i), &format!("value_{}", i))
+                .expect("insert should succeed");
+        }
+        // make some entries hot, so they end up in the main queue
+        for _ in 0..2 {
+            for i in 0..100 {
+                let _: Result<String, GetCacheError> = cache.get(&format!("key_{}", i));
+            }
+        }
+        cache
+            .insert_pinned("pinned".to_string(), &"config".to_string())
+            .expect("insert pinned should succeed");
+
+        let mut resident_before = Vec::new();
+        for i in 0..1000 {
+            if let Ok(value) = cache.get::<String>(&format!("key_{}", i)) {
+                resident_before.push((i, value));
+            }
+        }
+        let stats_before = cache.get_utilization_stats();
+        assert!(stats_before.2 > 0, "Some entries should be ghosts");
+        // concurrent saves to the same path write to their own temporary files
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| cache.save_snapshot(&path).expect("save should succeed"));
+            }
+        });
+
+        // ghosts don't depend on the seeds of the cache, a default one gets them back too
+        let default_cache = AlsoCache::<String, _, _>::default(20_000);
+        default_cache
+            .load_snapshot(&path)
+            .expect("load should succeed");
+        assert_eq!(default_cache.get_utilization_stats(), stats_before);
+        // so a key evicted before the snapshot goes to the main queue when inserted again
+        let evicted = (0..1000)
+            .find(|i| resident_before.iter().all(|(resident, _)| resident != i))
+            .expect("some key should be evicted");
+        let (_, main, ghosts, _) = stats_before;
+        default_cache
+            .insert(format!("key_{}", evicted), &format!("value_{}", evicted))
+            .expect("insert should succeed");
+        let (_, main_after, ghosts_after, _) = default_cache.get_utilization_stats();
+        assert!(main_after > main && ghosts_after < ghosts);
+
+        let restored = new_cache();
+        let count = restored.load_snapshot(&path).expect("load should succeed");
+        std::fs::remove_file(&path).expect("snapshot file should be removed");
+        assert!(count > 0, "Some entries should be restored");
+
+        // queues have the same weights, and all resident entries are back
+        assert_eq!(restored.get_utilization_stats(), stats_before);
+        for (i, value) in resident_before {
+            let retrieved: String = restored
+                .get(&format!("key_{}", i))
+                .expect("restored entry should be found");
+            assert_eq!(retrieved, value);
+        }
+        let pinned: String = restored
+            .get(&"pinned".to_string())
+            .expect("pinned entry should be restored");
+        assert_eq!(pinned, "config");
+    }
+
+    #[test]
+    fn test_snapshot_rejects_corruption() {
+        let path =
+            std::env::temp_dir().join(format!("also_cache_corrupted_{}.bin", std::process::id()));
+
+        // small cache has a single shard, the big one has several
+        let cache = AlsoCache::default(2000);
+        for i in 0..20 {
+            cache
+                .insert(format!("key_{}", i), &i)
+                .expect("insert should succeed");
+        }
+        cache.save_snapshot(&path).expect("save should succeed");
+        let bytes = std::fs::read(&path).expect("snapshot should be readable");
+
+        // keys are rehashed into a different number of shards
+        let bigger = AlsoCache::default(1_000_000);
+        assert_eq!(
+            bigger.load_snapshot(&path).expect("load should succeed"),
+            20
+        );
+        for i in 0..20 {
+            let value: i32 = bigger
+                .get(&format!("key_{}", i))
+                .expect("restored entry should be found");
+            assert_eq!(value, i);
+        }
+        // keys already present are not restored again
+        assert_eq!(bigger.load_snapshot(&path).expect("load should succeed"), 0);
+
+        // flipped byte in the last section (ghosts of the only shard)
+        let mut corrupted = bytes.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xFF;
+        std::fs::write(&path, &corrupted).expect("snapshot should be writable");
+        let restored = AlsoCache::<String, _, _>::default(2000);
+        let res = restored.load_snapshot(&path);
+        assert!(
+            matches!(res, Err(SnapshotError::ChecksumMismatch { section: 2 })),
+            "Corrupted snapshot should be rejected"
+        );
+        assert_eq!(restored.get_utilization_stats(), (0, 0, 0, 0));
+
+        // truncated file
+        std::fs::write(&path, &bytes[..bytes.len() - 10]).expect("snapshot should be writable");
+        let res = restored.load_snapshot(&path);
+        assert!(
+            matches!(res, Err(SnapshotError::Truncated)),
+            "Truncated snapshot should be rejected"
+        );
+        assert_eq!(restored.get_utilization_stats(), (0, 0, 0, 0));
+
+        // not a snapshot at all
+        std::fs::write(&path, b"definitely not a snapshot").expect("file should be writable");
+        let res = restored.load_snapshot(&path);
+        assert!(matches!(res, Err(SnapshotError::BadMagic)));
+
+        std::fs::remove_file(&path).expect("snapshot file should be removed");
+    }
+
+    #[test]
+    fn test_oplog_recovery() {
+        let dir = std::env::temp_dir();
+        let options = OpLogOptions {
+            fsync: FsyncPolicy::Always,
+            compact_after: None,
+            ..OpLogOptions::new(
+                dir.join(format!("also_cache_oplog_{}.log", std::process::id())),
+                dir.join(format!("also_cache_oplog_{}.bin", std::process::id())),
+            )
+        };
+
+        let mut cache = AlsoCache::default(2000);
+        assert_eq!(
+            cache
+                .open_oplog(options.clone())
+                .expect("open should succeed"),
+            0
+        );
+        for i in 0..10 {
+            cache
+                .insert(format!("key_{}", i), &i)
+                .expect("insert should succeed");
+        }
+        cache
+            .insert(format!("key_{}", 1), &100)
+            .expect("update should succeed");
+        assert!(cache.delete(&"key_3".to_string()));
+        cache
+            .incr("counter".to_string(), 5)
+            .expect("incr should succeed");
+        // process dies without a snapshot, in the middle of writing a record
+        drop(cache);
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&options.log_path)
+            .expect("log should exist");
+        std::io::Write::write_all(&mut log, &[42, 0, 0, 0]).expect("log should be writable");
+
+        let mut recovered = AlsoCache::default(2000);
+        assert_eq!(
+            recovered
+                .open_oplog(options.clone())
+                .expect("open should succeed"),
+            13
+        );
+        for i in (0..10).filter(|&i| i != 1 && i != 3) {
+            let value: i32 = recovered
+                .get(&format!("key_{}", i))
+                .expect("logged entry should be recovered");
+            assert_eq!(value, i);
+        }
+        assert_eq!(recovered.get::<i32>(&"key_1".to_string()).ok(), Some(100));
+        assert!(recovered.get::<i32>(&"key_3".to_string()).is_err());
+        assert_eq!(recovered.get::<i64>(&"counter".to_string()).ok(), Some(5));
+
+        // after compaction the log is empty again, and entries come from the snapshot
+        recovered
+            .insert("late".to_string(), &1)
+            .expect("insert should succeed");
+        recovered
+            .compact_oplog()
+            .expect("compaction should succeed");
+        drop(recovered);
+        let mut reopened = AlsoCache::<String, _, _>::default(2000);
+        assert_eq!(
+            reopened
+                .open_oplog(options.clone())
+                .expect("open should succeed"),
+            0
+        );
+        assert_eq!(reopened.get::<i32>(&"late".to_string()).ok(), Some(1));
+        assert_eq!(reopened.get::<i32>(&"key_1".to_string()).ok(), Some(100));
+
+        std::fs::remove_file(&options.log_path).expect("log should be removed");
+        std::fs::remove_file(&options.snapshot_path).expect("snapshot should be removed");
+    }
+
+    #[tokio::test]
+    async fn test_background_snapshots() {
+        let path =
+            std::env::temp_dir().join(format!("also_cache_background_{}.bin", std::process::id()));
+        let cache = std::sync::Arc::new(AlsoCache::default(2000));
+        let task = cache.spawn_snapshots(SnapshotSchedule {
+            every: None,
+            after_writes: Some(10),
+            poll_interval: std::time::Duration::from_millis(10),
+            ..SnapshotSchedule::new(&path)
+        });
+
+        for i in 0..5 {
+            cache
+                .insert(format!("key_{}", i), &i)
+                .expect("insert should succeed");
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(task.snapshots_written(), 0, "Not enough writes yet");
+
+        for i in 5..10 {
+            cache
+                .insert(format!("key_{}", i), &i)
+                .expect("insert should succeed");
+        }
+        for _ in 0..100 {
+            if task.snapshots_written() > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(task.snapshots_written(), 1);
+        assert!(task.take_error().is_none());
+        task.stop();
+
+        let restored = AlsoCache::<String, _, _>::default(2000);
+        assert_eq!(
+            restored.load_snapshot(&path).expect("load should succeed"),
+            10
+        );
+        std::fs::remove_file(&path).expect("snapshot file should be removed");
+    }
+
+    #[tokio::test]
+    async fn test_oplog_compaction() {
+        let dir = std::env::temp_dir();
+        let options = OpLogOptions {
+            compact_after: Some(10),
+            ..OpLogOptions::new(
+                dir.join(format!("also_cache_compaction_{}.log", std::process::id())),
+                dir.join(format!("also_cache_compaction_{}.bin", std::process::id())),
+            )
+        };
+        let log_len = || {
+            std::fs::metadata(&options.log_path)
+                .expect("log should exist")
+                .len()
+        };
+
+        let mut cache = AlsoCache::default(2000);
+        cache
+            .open_oplog(options.clone())
+            .expect("open should succeed");
+        let empty_len = log_len();
+        for i in 0..10 {
+            cache
+                .insert(format!("key_{}", i), &i)
+                .expect("insert should succeed");
+        }
+        // updates only ask for a compaction
+        assert!(cache.oplog_compaction_pending());
+        assert!(log_len() > empty_len);
+        cache.compact_oplog().expect("compaction should succeed");
+        assert!(!cache.oplog_compaction_pending());
+        assert_eq!(log_len(), empty_len);
+
+        // the snapshot task compacts the log when it is due
+        let cache = std::sync::Arc::new(cache);
+        let task = cache.spawn_snapshots(SnapshotSchedule {
+            every: None,
+            poll_interval: std::time::Duration::from_millis(10),
+            ..SnapshotSchedule::new(dir.join(format!(
+                "also_cache_compaction_task_{}.bin",
+                std::process::id()
+            )))
+        });
+        for i in 10..20 {
+            cache
+                .insert(format!("key_{}", i), &i)
+                .expect("insert should succeed");
+        }
+        for _ in 0..100 {
+            if !cache.oplog_compaction_pending() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!cache.oplog_compaction_pending());
+        assert!(task.take_error().is_none());
+        task.stop();
+        assert_eq!(log_len(), empty_len);
+
+        std::fs::remove_file(&options.log_path).expect("log should be removed");
+        std::fs::remove_file(&options.snapshot_path).expect("snapshot should be removed");
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let cache = AlsoCache::default(2000);
+        let users = cache.namespace("users");
+        let sessions = cache.namespace("sessions");
+        assert_ne!(users.id(), sessions.id());
+        assert_eq!(cache.namespace("").id(), namespace_id(""));
+
+        // same key in every namespace, each with a value of its own type
+        let key = "a".to_string();
+        users
+            .insert(key.clone(), &1u32)
+            .expect("insert should succeed");
+        sessions
+            .insert(key.clone(), &"token".to_string())
+            .expect("insert should succeed");
+        cache
+            .insert(key.clone(), &true)
+            .expect("insert should succeed");
+        assert_eq!(users.get::<u32>(&key).ok(), Some(1));
+        assert_eq!(sessions.get::<String>(&key).ok(), Some("token".to_string()));
+        assert_eq!(cache.get::<bool>(&key).ok(), Some(true));
+
+        for i in 0..10 {
+            sessions
+                .insert(format!("session_{}", i), &"token".to_string())
+                .expect("insert should succeed");
+        }
+        assert_eq!(users.stats().entries, 1);
+        assert_eq!(sessions.stats().entries, 11);
+        assert!(sessions.stats().weight > users.stats().weight);
+
+        assert_eq!(sessions.clear(), 11);
+        assert_eq!(sessions.stats(), NamespaceStats::default());
+        assert!(matches!(
+            sessions.get::<String>(&key),
+            Err(GetCacheError::KeyNotFound)
+        ));
+        assert_eq!(users.get::<u32>(&key).ok(), Some(1));
+        assert_eq!(cache.get::<bool>(&key).ok(), Some(true));
+
+        let mut names = cache.namespace_names();
+        names.sort();
+        assert_eq!(names, ["", "sessions", "users"]);
+    }
+
+    #[test]
+    fn test_namespace_quotas() {
+        // single shard: small queue 200, main queue 1800
+        let cache = AlsoCache::default(2000);
+        let noisy = cache.namespace("noisy");
+        let quiet = cache.namespace("quiet");
+        let other = cache.namespace("other");
+        let value = vec![7u8; 40];
+
+        noisy
+            .set_quota(NamespaceQuota {
+                max_weight: Some(300),
+                min_weight: 0,
+            })
+            .expect("quota should be set");
+        quiet
+            .set_quota(NamespaceQuota {
+                max_weight: None,
+                min_weight: 400,
+            })
+            .expect("quota should be set");
+
+        // hot entries, so they end up in the main queue
+        for i in 0..10 {
+            for namespace in [&quiet, &other] {
+                namespace.insert(i, &value).expect("insert should succeed");
+                let _: Vec<u8> = namespace.get(&i).expect("entry should be found");
+            }
+        }
+
+        // noisy namespace can't grow over its maximum, even though there is space left
+        for i in 0..200 {
+            noisy.insert(i, &value).expect("insert should succeed");
+            assert!(noisy.stats().weight <= 300);
+        }
+        assert_eq!(quiet.stats().entries, 10);
+        assert_eq!(other.stats().entries, 10);
+
+        // pinned entries weigh more than the maximum, but they don't count against it
+        let pinned = cache.namespace("pinned");
+        pinned
+            .set_quota(NamespaceQuota {
+                max_weight: Some(300),
+                min_weight: 0,
+            })
+            .expect("quota should be set");
+        for i in 0..10 {
+            pinned
+                .insert_pinned(i, &value)
+                .expect("insert should succeed");
+        }
+        assert!(pinned.stats().weight > 300);
+        for i in 10..13 {
+            pinned.insert(i, &value).expect("insert should succeed");
+        }
+        assert_eq!(pinned.stats().entries, 13);
+
+        // without a maximum, hot noisy entries push out everything but the guaranteed minimum
+        noisy.remove_quota().expect("quota should be removed");
+        for i in 0..200 {
+            noisy.insert(i, &value).expect("insert should succeed");
+            let _: Vec<u8> = noisy.get(&i).expect("entry should be found");
+        }
+        assert_eq!(quiet.stats().entries, 10);
+        assert_eq!(other.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_tag_invalidation() {
+        // several shards, tagged entries are spread across them
+        let cache = AlsoCache::default(1_000_000);
+        let fragments = cache.namespace("fragments");
+        for i in 0..100 {
+            let tags: &[&str] = if i % 2 == 0 {
+                &["product:1", "home"]
+            } else {
+                &["product:2"]
+            };
+            cache
+                .insert_tagged(format!("page_{}", i), &i, tags)
+                .expect("insert should succeed");
+            fragments
+                .insert_tagged(format!("fragment_{}", i), &i, tags)
+                .expect("insert should succeed");
+        }
+        cache
+            .insert("untagged".to_string(), &0)
+            .expect("insert should succeed");
+
+        // updates replace tags, deleted entries leave the index
+        cache
+            .insert("page_0".to_string(), &0)
+            .expect("update should succeed");
+        assert!(cache.delete(&"page_2".to_string()));
+
+        assert_eq!(cache.invalidate_tag("product:1"), 98);
+        assert_eq!(cache.invalidate_tag("product:1"), 0);
+        assert_eq!(cache.invalidate_tag("home"), 0);
+        assert_eq!(cache.get::<i32>(&"page_0".to_string()).ok(), Some(0));
+        for i in (1..100).step_by(2) {
+            assert_eq!(cache.get::<i32>(&format!("page_{}", i)).ok(), Some(i));
+            assert!(cache.get::<i32>(&format!("page_{}", i + 1)).is_err());
+            assert!(
+                fragments
+                    .get::<i32>(&format!("fragment_{}", i - 1))
+                    .is_err()
+            );
+        }
+
+        assert_eq!(cache.invalidate_tag("product:2"), 100);
+        assert_eq!(fragments.stats().entries, 0);
+        assert_eq!(cache.get::<i32>(&"untagged".to_string()).ok(), Some(0));
+
+        // value-only updates (compare-and-set, counters) keep the tags
+        let product = "product".to_string();
+        cache
+            .insert_tagged(product.clone(), &"old", &["product"])
+            .expect("insert should succeed");
+        let (_, version): (String, u64) = cache
+            .get_with_version(&product)
+            .expect("get should succeed");
+        cache
+            .compare_and_set(product.clone(), version, &"new")
+            .expect("compare and set should succeed");
+        let views = "views".to_string();
+        cache
+            .insert_tagged(views.clone(), &0i64, &["product"])
+            .expect("insert should succeed");
+        cache.incr(views.clone(), 1).expect("incr should succeed");
+        assert_eq!(cache.invalidate_tag("product"), 2);
+        assert!(cache.get::<String>(&product).is_err());
+        assert!(cache.get::<i64>(&views).is_err());
+    }
+
+    #[test]
+    fn test_generations() {
+        let dir = std::env::temp_dir();
+        let options = OpLogOptions::new(
+            dir.join(format!("also_cache_generations_{}.log", std::process::id())),
+            dir.join(format!("also_cache_generations_{}.bin", std::process::id())),
+        );
+        let mut cache = AlsoCache::default(2000);
+        cache
+            .open_oplog(options.clone())
+            .expect("open should succeed");
+        let pages = cache.namespace("pages");
+        for i in 0..10 {
+            pages.insert(i, &i).expect("insert should succeed");
+            cache.insert(i, &i).expect("insert should succeed");
+        }
+
+        let used = cache.get_utilization_stats().0;
+        assert_eq!(pages.bump_generation(), 1);
+        for i in 0..10 {
+            assert!(matches!(
+                pages.get::<i32>(&i),
+                Err(GetCacheError::KeyNotFound)
+            ));
+            assert_eq!(cache.get::<i32>(&i).ok(), Some(i));
+        }
+        assert_eq!(pages.stats().entries, 0);
+        // stale entries are still there, until they are evicted
+        assert_eq!(cache.get_utilization_stats().0, used);
+
+        pages.insert(0, &100).expect("insert should succeed");
+        assert_eq!(pages.get::<i32>(&0).ok(), Some(100));
+        assert_eq!(cache.bump_generation(""), Some(1));
+        assert!(cache.get::<i32>(&0).is_err());
+        // unknown namespaces are not created
+        assert_eq!(cache.bump_generation("unknown"), None);
+        assert_eq!(cache.namespace_names().len(), 2);
+
+        // generations survive a restart, so stale entries don't come back
+        drop(pages);
+        drop(cache);
+        let mut restored = AlsoCache::<i32, _, _>::default(2000);
+        restored
+            .open_oplog(options.clone())
+            .expect("open should succeed");
+        let pages = restored.namespace("pages");
+        assert_eq!(pages.generation(), 1);
+        assert_eq!(pages.get::<i32>(&0).ok(), Some(100));
+        assert!(pages.get::<i32>(&1).is_err());
+        assert!(restored.get::<i32>(&0).is_err());
+
+        std::fs::remove_file(&options.log_path).expect("log should be removed");
+        std::fs::remove_file(&options.snapshot_path).expect("snapshot should be removed");
+    }
+
+    #[test]
+    fn test_eviction_count() {
+        // a single shard with a small queue of 100 and entries of weight 1
+        let cache = AlsoCache::default(1000);
+        for i in 0..500 {
+            cache.insert(i, &0u8).expect("insert should succeed");
+        }
+        assert_eq!(cache.get_eviction_count(), 400);
+
+        // deletes are not evictions
+        assert!(cache.delete(&499));
+        assert_eq!(cache.get_eviction_count(), 400);
+    }
+
+    #[test]
+    fn test_access_trace_recording() {
+        let path =
+            std::env::temp_dir().join(format!("also_cache_trace_{}.bin", std::process::id()));
+        // recording is started and stopped on a shared cache
+        let cache = Arc::new(AlsoCache::default(2000));
+        let mut options = TraceOptions::new(&path);
+        options.sample_one_in = 1;
+        cache
+            .start_recording(options)
+            .expect("recording should start");
+        for i in 0..10 {
+            cache.insert(i, &"value").expect("insert should succeed");
+        }
+        for i in 0..20 {
+            let _ = cache.get::<String>(&i);
+        }
+        assert!(cache.delete(&0));
+        assert!(cache.pin(&1)); // not an access
+        assert_eq!(cache.stop_recording().expect("trace should be flushed"), 31);
+
+        let trace = read_trace(&path).expect("trace should be readable");
+        assert_eq!(trace.sample_one_in, 1);
+        let count = |op| trace.records.iter().filter(|r| r.op == op).count();
+        assert_eq!(count(TraceOp::Insert), 10);
+        assert_eq!(count(TraceOp::GetHit), 10);
+        assert_eq!(count(TraceOp::GetMiss), 10);
+        assert_eq!(count(TraceOp::Delete), 1);
+        let value_size = bincode::serde::encode_to_vec("value", bincode::config::standard())
+            .expect("value should be encoded")
+            .len() as u32;
+        assert_eq!(trace.records[0].size, value_size);
+        // same key, same hash
+        assert_eq!(trace.records[0].key_hash, trace.records[10].key_hash);
+        assert_eq!(trace.records[10].size, value_size);
+        assert!(trace.records.windows(2).all(|w| w[0].micros <= w[1].micros));
+
+        // sampling keeps all accesses of a sampled key, and the trace is bounded
+        let mut options = TraceOptions::new(&path);
+        options.sample_one_in = 4;
+        options.max_records = Some(300);
+        cache
+            .start_recording(options)
+            .expect("recording should start");
+        for i in 0..1000 {
+            cache.insert(i, &i).expect("insert should succeed");
+            cache.get::<i32>(&i).expect("get should succeed");
+        }
+        let recorded = cache.stop_recording().expect("trace should be flushed");
+        let trace = read_trace(&path).expect("trace should be readable");
+        assert_eq!(recorded, 300);
+        assert_eq!(trace.records.len(), 300);
+        assert!(trace.records.chunks(2).all(|pair| {
+            pair[0].op == TraceOp::Insert
+                && pair[1].op == TraceOp::GetHit
+                && pair[0].key_hash == pair[1].key_hash
+        }));
+
+        std::fs::remove_file(&path).expect("trace should be removed");
+    }
+
+    #[test]
+    fn test_eviction_policies() {
+        // single shard holding 100 entries of weight 10
+        let cache_with = |policy: Arc<dyn EvictionPolicy>| {
+            let options = Options {
+                eviction_policy: policy,
+                ..Options::default()
+            };
+            AlsoCache::with_options(0, 1000, DefaultWeighter, RandomState::new(), options)
+        };
+        let value = vec![0u8; 9];
+        for policy in [Arc::new(Lru) as Arc<dyn EvictionPolicy>, Arc::new(Clock)] {
+            let cache = cache_with(policy.clone());
+            for i in 0..100 {
+                cache.insert(i, &value).expect("insert should succeed");
+            }
+            // 0 is accessed after 1
+            assert!(cache.get::<Vec<u8>>(&1).is_ok());
+            assert!(cache.get::<Vec<u8>>(&0).is_ok());
+            for i in 100..199 {
+                cache.insert(i, &value).expect("insert should succeed");
+            }
+            assert_eq!(cache.get_eviction_count(), 99, "{}", policy.name());
+            assert_eq!(cache.get_utilization_stats().1, 1000, "{}", policy.name());
+            assert!(cache.get::<Vec<u8>>(&0).is_ok(), "{}", policy.name());
+            assert!(cache.get::<Vec<u8>>(&2).is_err(), "{}", policy.name());
+            // LRU evicts the least recently accessed entry, CLOCK gives both a second chance
+            assert_eq!(
+                cache.get::<Vec<u8>>(&1).is_ok(),
+                policy.name() == "clock",
+                "{}",
+                policy.name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_tinylfu_admission() {
+        // single shard: small queue holds 10 entries of weight 10, main queue 90
+        let popular_kept = |tinylfu_admission| {
+            let options = Options {
+                tinylfu_admission,
+                ..Options::default()
+            };
+            // fixed seeds: with unlucky ones a popular key can share sketch counters with
+            // the oldest main queue entry and lose to it
+            let hasher = RandomState::with_seeds(1, 2, 3, 4);
+            let cache = AlsoCache::with_options(0, 1000, DefaultWeighter, hasher, options);
+            let value = vec![0u8; 9];
+            for i in 0..90 {
+                cache.insert(i, &value).expect("insert should succeed");
+                for _ in 0..5 {
+                    cache.get::<Vec<u8>>(&i).expect("get should succeed");
+                }
+            }
+            // long tail of keys that are requested twice and never again
+            for i in 1000..1500 {
+                cache.insert(i, &value).expect("insert should succeed");
+                cache.get::<Vec<u8>>(&i).expect("get should succeed");
+            }
+            (0..90).filter(|i| cache.get::<Vec<u8>>(i).is_ok()).count()
+        };
+        assert_eq!(popular_kept(true), 90);
+        assert!(popular_kept(false) < 45);
+
+        // a rejected key leaves a ghost, writing it again must not get it into the main queue
+        let options = Options {
+            tinylfu_admission: true,
+            ..Options::default()
+        };
+        let hasher = RandomState::with_seeds(1, 2, 3, 4);
+        let cache = AlsoCache::with_options(0, 1000, DefaultWeighter, hasher, options);
+        let value = vec![0u8; 9];
+        for i in 0..90 {
+            cache.insert(i, &value).expect("insert should succeed");
+            for _ in 0..5 {
+                cache.get::<Vec<u8>>(&i).expect("get should succeed");
+            }
+        }
+        for i in 1000..1500 {
+            cache.insert(i, &value).expect("insert should succeed");
+            cache.get::<Vec<u8>>(&i).expect("get should succeed");
+            if i >= 1020 {
+                // rejected when it left the small queue, only its ghost is left
+                cache.insert(i - 20, &value).expect("insert should succeed");
+            }
+        }
+        assert_eq!(
+            (0..90).filter(|i| cache.get::<Vec<u8>>(i).is_ok()).count(),
+            90
+        );
+    }
+
+    #[test]
+    fn test_ghost_fingerprints() {
+        // a single shard with a small queue of 100 and entries of weight 1
+        let cache = AlsoCache::default(1000);
+        for i in 0..200 {
+            cache.insert(i, &0u8).expect("insert should succeed");
+        }
+        // keys 0..100 were evicted from the small queue and left a ghost
+        assert_eq!(cache.get_utilization_stats(), (100, 0, 100, 1));
+        assert!(cache.get::<u8>(&0).is_err());
+
+        // a key with a ghost goes straight to the main queue, and its ghost is forgotten
+        cache.insert(0, &0u8).expect("insert should succeed");
+        assert_eq!(cache.get_utilization_stats(), (100, 1, 99, 1));
+        assert_eq!(cache.get::<u8>(&0).expect("get should succeed"), 0);
+
+        // ghost queue is bounded by its threshold (half of the shard)
+        for i in 200..2000 {
+            cache.insert(i, &0u8).expect("insert should succeed");
+        }
+        let (_, _, ghost, _) = cache.get_utilization_stats();
+        assert_eq!(ghost, 500);
+    }
+
+    #[test]
+    fn test_frequency_cap_and_decay() {
+        use crate::cache_shard::CacheShard;
+        use crate::namespace::{DEFAULT_NAMESPACE, NsKey};
+        use std::time::Duration;
+
+        let key = NsKey {
+            namespace: DEFAULT_NAMESPACE,
+            generation: 0,
+            key: 1u64,
+        };
+        let freq_after_gets = |options: Options, gets| {
+            let mut shard = CacheShard::with_options(0, 100, 900, 500, options, RandomState::new());
+            shard
+                .insert_bytes(key.clone(), 1, vec![0])
+                .expect("insert should succeed");
+            for _ in 0..gets {
+                assert!(shard.get_bytes(&key).is_some());
+            }
+            (
+                shard
+                    .export_entry(&key)
+                    .expect("entry should be there")
+                    .freq,
+                shard,
+            )
+        };
+        assert_eq!(freq_after_gets(Options::default(), 10).0, 3);
+
+        let options = Options {
+            max_frequency: 7,
+            frequency_decay_interval: Some(Duration::from_millis(300)),
+            ..Options::default()
+        };
+        let (freq, mut shard) = freq_after_gets(options, 10);
+        assert_eq!(freq, 7);
+        // after the interval, the next access halves the frequency before counting itself
+        std::thread::sleep(Duration::from_millis(350));
+        assert!(shard.get_bytes(&key).is_some());
+        assert_eq!(
+            shard
+                .export_entry(&key)
+                .expect("entry should be there")
+                .freq,
+            4
+        );
+    }
+
+    #[test]
+    fn test_hot_keys() {
+        let options = Options {
+            hot_keys_tracked: 16,
+            ..Options::default()
+        };
+        let cache =
+            AlsoCache::with_options(0, 100_000, DefaultWeighter, RandomState::new(), options);
+        for i in 0..200u64 {
+            cache.insert(i, &i).expect("insert should succeed");
+        }
+        for _ in 0..100 {
+            cache.get::<u64>(&7).expect("get should succeed");
+        }
+        let users = cache.namespace("users");
+        users.insert(3, &3u64).expect("insert should succeed");
+        for _ in 0..50 {
+            users.get::<u64>(&3).expect("get should succeed");
+        }
+
+        let hot_keys = cache.hot_keys(2);
+        assert_eq!(hot_keys.len(), 2);
+        assert_eq!((hot_keys[0].key, hot_keys[0].namespace.as_str()), (7, ""));
+        assert_eq!(
+            (hot_keys[1].key, hot_keys[1].namespace.as_str()),
+            (3, "users")
+        );
+        for (hot_key, accesses) in hot_keys.iter().zip([101, 51]) {
+            assert!(hot_key.count >= accesses);
+            assert!(hot_key.count - hot_key.error <= accesses);
+        }
+
+        // entries of older generations are not reported
+        users.bump_generation();
+        assert!(
+            cache
+                .hot_keys(10)
+                .iter()
+                .all(|hot_key| hot_key.namespace.is_empty())
+        );
+
+        // a key that only misses is counted but not reported, until its miss is cached
+        for _ in 0..200 {
+            assert!(cache.get::<u64>(&1000).is_err());
+        }
+        assert_eq!(cache.hot_keys(1)[0].key, 7);
+        cache
+            .insert_absent(1000, std::time::Duration::from_secs(60))
+            .expect("insert should succeed");
+        assert_eq!(cache.hot_keys(1)[0].key, 1000);
+
+        // tracking is disabled by default
+        let cache = AlsoCache::default(1000);
+        cache.insert(1, &1u64).expect("insert should succeed");
+        cache.get::<u64>(&1).expect("get should succeed");
+        assert!(cache.hot_keys(10).is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        // single shard: small queue 200, main queue 1800
+        let cache = AlsoCache::default(2000);
+        let limited = cache.namespace("limited");
+        limited
+            .set_quota(NamespaceQuota {
+                max_weight: Some(300),
+                min_weight: 0,
+            })
+            .expect("quota should be set");
+        let value = vec![0u8; 20];
+        for i in 0..200u64 {
+            match i % 5 {
+                0 => cache
+                    .insert_pinned(i, &value)
+                    .expect("insert should succeed"),
+                1 => limited.insert(i, &value).expect("insert should succeed"),
+                2 => cache
+                    .insert_tagged(i, &value, &["tag"])
+                    .expect("insert should succeed"),
+                3 => cache
+                    .insert_absent(i, std::time::Duration::from_secs(60))
+                    .expect("insert should succeed"),
+                _ => cache.insert(i, &value).expect("insert should succeed"),
+            }
+            let _ = cache.get::<Vec<u8>>(&(i / 2));
+            if i % 7 == 0 {
+                cache.delete(&(i / 3));
+            }
+        }
+        cache.invalidate_tag("tag");
+        assert!(cache.unpin(&5));
+
+        let reports = cache.validate();
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let (small, main, ghost, _) = cache.get_utilization_stats();
+        assert_eq!(
+            (report.small_size, report.main_size, report.ghost_size),
+            (small, main, ghost)
+        );
+        assert!(report.pinned_entries > 0 && report.ghosts > 0 && report.free_nodes > 0);
+    }
+
+    #[test]
+    fn test_inspect() {
+        // a single shard with a small queue of 100 and entries of weight 1
+        let cache = AlsoCache::default(1000);
+        for i in 0..50u64 {
+            cache.insert(i, &0u8).expect("insert should succeed");
+        }
+        cache.get::<u8>(&0).expect("get should succeed");
+        for i in 50..150u64 {
+            cache.insert(i, &0u8).expect("insert should succeed");
+        }
+        cache.get::<u8>(&51).expect("get should succeed");
+
+        let shards = cache.inspect(3);
+        assert_eq!(shards.len(), 1);
+        let shard = &shards[0];
+        // 0 was accessed in the small queue and promoted, 1..50 left ghosts
+        assert_eq!((shard.small.len, shard.small.size), (100, 100));
+        assert_eq!((shard.main.len, shard.pinned.len), (1, 0));
+        assert_eq!(shard.main.oldest[0].key.key, 0);
+        assert_eq!((shard.ghosts, shard.ghost_size, shard.keys), (49, 49, 101));
+        let oldest: Vec<_> = shard
+            .small
+            .oldest
+            .iter()
+            .map(|entry| (entry.key.key, entry.freq))
+            .collect();
+        assert_eq!(oldest, [(50, 0), (51, 1), (52, 0)]);
+        assert_eq!(shard.evictions, 49);
+
+        // reports can be sent over the wire
+        bincode::serde::encode_to_vec(&shards, bincode::config::standard())
+            .expect("shard info should serialize");
+    }
+
+    #[tokio::test]
+    async fn test_cluster_membership() {
+        use std::time::Duration;
+        use tokio::time::timeout;
+
+        let localhost = "127.0.0.1:0".parse().expect("address should parse");
+        let first = Cluster::start(ClusterOptions::new(localhost, Vec::new()))
+            .await
+            .expect("first node should start");
+        let mut events = first.subscribe();
+        let second = Cluster::start(ClusterOptions::new(localhost, vec![first.local_addr()]))
+            .await
+            .expect("second node should join");
+        let second_id = second.identity();
+
+        let joined = timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("join should be noticed")
+            .expect("events should be received");
+        assert_eq!(joined, MembershipEvent::Up(second_id.clone()));
+        assert_eq!(first.members(), std::slice::from_ref(&second_id));
+        for _ in 0..100 {
+            if !second.members().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(second.members(), [first.identity()]);
+
+        second.leave().await.expect("second node should leave");
+        let left = timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("leave should be noticed")
+            .expect("events should be received");
+        assert_eq!(left, MembershipEvent::Down(second_id));
+        assert!(first.members().is_empty());
+    }
+
     // #[test]
     // fn test_node_size_comparison() {
     //     use crate::cache_nodes_arena::{NodeIndex, QueueTypeId};
@@ -0,0 +1,309 @@
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use hashbrown::Equivalent;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::cache_shard::ShardError;
+use crate::sync::{AlsoCache, GetCacheError, InsertCacheError, Weighter};
+
+/// Id of a namespace, derived from its name (see `namespace_id`).
+pub type NamespaceId = u64;
+
+/// Namespace used by the methods of `AlsoCache` itself.
+pub const DEFAULT_NAMESPACE: NamespaceId = 0;
+
+/// Key of an entry together with its namespace. Shards of `AlsoCache` are keyed by it,
+/// so namespaces share the queues and the capacity, but not the keys.
+///
+/// Generation of the namespace is part of the key too: after `Namespace::bump_generation`
+/// keys of older entries don't match anymore, and these entries are evicted as usual.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NsKey<Key> {
+    pub namespace: NamespaceId,
+    pub generation: u64,
+    pub key: Key,
+}
+
+/// Keys that belong to a namespace, so shards can account weight per namespace.
+pub trait Namespaced {
+    fn namespace(&self) -> NamespaceId;
+}
+
+impl<Key> Namespaced for NsKey<Key> {
+    fn namespace(&self) -> NamespaceId {
+        self.namespace
+    }
+}
+
+// Borrowed form of `NsKey` used for lookups, so keys are not cloned.
+// Fields are hashed in the same order as in `NsKey`, so both have the same hash.
+#[derive(Hash)]
+pub(crate) struct NsKeyRef<'a, Key> {
+    namespace: NamespaceId,
+    generation: u64,
+    key: &'a Key,
+}
+
+impl<Key: Eq> Equivalent<NsKey<Key>> for NsKeyRef<'_, Key> {
+    fn equivalent(&self, other: &NsKey<Key>) -> bool {
+        self.namespace == other.namespace
+            && self.generation == other.generation
+            && *self.key == other.key
+    }
+}
+
+// Registered namespace, shared by the cache and the handles of the namespace
+#[derive(Debug)]
+pub(crate) struct NamespaceState {
+    pub(crate) id: NamespaceId,
+    pub(crate) name: String,
+    generation: AtomicU64,
+}
+
+impl NamespaceState {
+    pub(crate) fn new(name: &str, generation: u64) -> Self {
+        NamespaceState {
+            id: namespace_id(name),
+            name: name.to_string(),
+            generation: AtomicU64::new(generation),
+        }
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // Returns the new generation
+    pub(crate) fn bump_generation(&self) -> u64 {
+        self.generation.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    // Moves generation forward to `generation` (when restoring), never backwards
+    pub(crate) fn restore_generation(&self, generation: u64) {
+        self.generation.fetch_max(generation, Ordering::AcqRel);
+    }
+
+    pub(crate) fn key<Key>(&self, key: Key) -> NsKey<Key> {
+        NsKey {
+            namespace: self.id,
+            generation: self.generation(),
+            key,
+        }
+    }
+
+    pub(crate) fn key_ref<'a, Key>(&self, key: &'a Key) -> NsKeyRef<'a, Key> {
+        NsKeyRef {
+            namespace: self.id,
+            generation: self.generation(),
+            key,
+        }
+    }
+}
+
+/// Namespace and its generation, as saved in snapshots and operation logs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedNamespace {
+    pub name: String,
+    pub generation: u64,
+}
+
+/// Returns the id of the namespace with the given name (64-bit FNV-1a of the name).
+/// Ids don't depend on the order namespaces are opened in, so snapshots and operation logs
+/// stay valid after a restart. The empty name is the default namespace.
+pub fn namespace_id(name: &str) -> NamespaceId {
+    if name.is_empty() {
+        return DEFAULT_NAMESPACE;
+    }
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in name.as_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    // never collide with the default namespace
+    hash.max(1)
+}
+
+/// Number and total weight of live entries of a namespace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceStats {
+    pub entries: u64,
+    pub weight: u64,
+}
+
+/// Weight limits of a namespace, see `Namespace::set_quota`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceQuota {
+    /// When the namespace grows over this weight, its own oldest entries are evicted
+    /// first, no matter how much space is left in the cache. Pinned entries count in the
+    /// weight of the namespace but not against this limit, as they can't be evicted.
+    pub max_weight: Option<u64>,
+    /// Entries of the namespace are not evicted to make room for other namespaces while
+    /// the namespace weighs less than this. Minimums of all namespaces should fit in the cache.
+    pub min_weight: u64,
+}
+
+/// Handle to a namespace of an `AlsoCache`, returned by `AlsoCache::namespace`.
+/// Its methods work like the methods of the cache with the same names, but only see
+/// the entries of this namespace.
+pub struct Namespace<'a, Key, We, B> {
+    cache: &'a AlsoCache<Key, We, B>,
+    state: Arc<NamespaceState>,
+}
+
+impl<Key, We, B> Clone for Namespace<'_, Key, We, B> {
+    fn clone(&self) -> Self {
+        Namespace {
+            cache: self.cache,
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl<'a, Key, We, B> Namespace<'a, Key, We, B>
+where
+    Key: Eq + Hash + Clone,
+    We: Weighter<Key>,
+    B: BuildHasher + Clone,
+{
+    pub(crate) fn new(cache: &'a AlsoCache<Key, We, B>, state: Arc<NamespaceState>) -> Self {
+        Namespace { cache, state }
+    }
+
+    pub fn id(&self) -> NamespaceId {
+        self.state.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.state.name
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.state.generation()
+    }
+
+    /// Makes every entry of the namespace a miss at once, without going through the entries:
+    /// they stay in the cache until they are evicted as usual. Returns the new generation.
+    pub fn bump_generation(&self) -> u64 {
+        self.cache.bump_generation_in(&self.state)
+    }
+
+    pub fn get<V: DeserializeOwned>(&self, key: &Key) -> Result<V, GetCacheError> {
+        self.cache.get_in(&self.state, key)
+    }
+
+    pub fn get_with_version<V: DeserializeOwned>(
+        &self,
+        key: &Key,
+    ) -> Result<(V, u64), GetCacheError> {
+        self.cache.get_with_version_in(&self.state, key)
+    }
+
+    pub fn compare_and_set<V: Serialize>(
+        &self,
+        key: Key,
+        expected_version: u64,
+        val: &V,
+    ) -> Result<u64, InsertCacheError> {
+        self.cache
+            .compare_and_set_in(&self.state, key, expected_version, val)
+    }
+
+    pub fn get_or_load<V, F>(
+        &self,
+        key: &Key,
+        negative_ttl: Duration,
+        loader: F,
+    ) -> Result<Option<V>, GetCacheError>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce(&Key) -> Option<V>,
+    {
+        self.cache
+            .get_or_load_in(&self.state, key, negative_ttl, loader)
+    }
+
+    pub fn insert<V: Serialize>(&self, key: Key, val: &V) -> Result<(), InsertCacheError> {
+        self.cache.insert_in(&self.state, key, val)
+    }
+
+    pub fn insert_tagged<V: Serialize>(
+        &self,
+        key: Key,
+        val: &V,
+        tags: &[&str],
+    ) -> Result<(), InsertCacheError> {
+        self.cache.insert_tagged_in(&self.state, key, val, tags)
+    }
+
+    pub fn insert_with_cost<V: Serialize>(
+        &self,
+        key: Key,
+        val: &V,
+        cost: u32,
+    ) -> Result<(), InsertCacheError> {
+        self.cache.insert_with_cost_in(&self.state, key, val, cost)
+    }
+
+    pub fn insert_pinned<V: Serialize>(&self, key: Key, val: &V) -> Result<(), InsertCacheError> {
+        self.cache.insert_pinned_in(&self.state, key, val)
+    }
+
+    pub fn insert_absent(&self, key: Key, ttl: Duration) -> Result<(), InsertCacheError> {
+        self.cache.insert_absent_in(&self.state, key, ttl)
+    }
+
+    pub fn incr(&self, key: Key, delta: i64) -> Result<i64, InsertCacheError> {
+        self.cache.add_to_counter(&self.state, key, delta, None)
+    }
+
+    pub fn decr(&self, key: Key, delta: i64) -> Result<i64, InsertCacheError> {
+        self.cache
+            .add_to_counter(&self.state, key, delta.saturating_neg(), None)
+    }
+
+    pub fn incr_with_ttl(
+        &self,
+        key: Key,
+        delta: i64,
+        ttl: Duration,
+    ) -> Result<i64, InsertCacheError> {
+        self.cache
+            .add_to_counter(&self.state, key, delta, Some(ttl))
+    }
+
+    pub fn pin(&self, key: &Key) -> bool {
+        self.cache.pin_in(&self.state, key)
+    }
+
+    pub fn unpin(&self, key: &Key) -> bool {
+        self.cache.unpin_in(&self.state, key)
+    }
+
+    pub fn delete(&self, key: &Key) -> bool {
+        self.cache.delete_in(&self.state, key)
+    }
+
+    /// Removes all entries of the namespace. Returns number of removed entries.
+    /// Shards are locked one at a time, so entries inserted concurrently may survive.
+    pub fn clear(&self) -> usize {
+        self.cache.clear_in(&self.state)
+    }
+
+    /// Sets weight limits of the namespace. They are split evenly between the shards,
+    /// since keys are spread evenly across them.
+    pub fn set_quota(&self, quota: NamespaceQuota) -> Result<(), ShardError> {
+        self.cache.set_quota_in(&self.state, Some(quota))
+    }
+
+    pub fn remove_quota(&self) -> Result<(), ShardError> {
+        self.cache.set_quota_in(&self.state, None)
+    }
+
+    /// Counts entries of the namespace, locking shards one at a time.
+    pub fn stats(&self) -> NamespaceStats {
+        self.cache.stats_in(&self.state)
+    }
+}
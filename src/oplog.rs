@@ -0,0 +1,243 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::namespace::SavedNamespace;
+use crate::snapshot::{
+    SNAPSHOT_CODEC, SnapshotError, encode_section, read_error, read_section, tmp_path,
+    write_section,
+};
+
+// Operation log file layout:
+// - magic number (8 bytes)
+// - header section (format version and codec)
+// - one section per record, framed the same way as snapshot sections
+//
+// A record holds the state of a key right after it was changed (or that it was removed),
+// so replaying a record is idempotent, and replaying the log on top of a snapshot taken
+// after some of its records were written still gives the newest state.
+
+pub const OPLOG_MAGIC: [u8; 8] = *b"ALSOOPLG";
+pub const OPLOG_FORMAT_VERSION: u32 = 4;
+
+/// When the operation log is flushed to disk. Records are always written to the file
+/// right away, so they survive a crash of the process, but not of the machine.
+///
+/// Writing a record is one `write` call, made under a lock shared by all shards (and
+/// followed by the sync with `Always`), so with the log enabled this bounds the rate of
+/// updates of the whole cache, however many shards it has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Sync after every record.
+    Always,
+    /// Sync after every `n` records.
+    Every(u64),
+    /// Leave it to the OS.
+    Never,
+}
+
+/// Configuration of the operation log, see `AlsoCache::open_oplog`.
+#[derive(Debug, Clone)]
+pub struct OpLogOptions {
+    pub log_path: PathBuf,
+    /// Snapshot the log is compacted into
+    pub snapshot_path: PathBuf,
+    pub fsync: FsyncPolicy,
+    /// Compact the log after this many records, in the background task of
+    /// `AlsoCache::spawn_snapshots`. `None` means it is only compacted by explicit calls
+    /// of `AlsoCache::compact_oplog`.
+    pub compact_after: Option<u64>,
+}
+
+impl OpLogOptions {
+    pub fn new(log_path: impl Into<PathBuf>, snapshot_path: impl Into<PathBuf>) -> Self {
+        OpLogOptions {
+            log_path: log_path.into(),
+            snapshot_path: snapshot_path.into(),
+            fsync: FsyncPolicy::Every(64),
+            compact_after: Some(100_000),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OpLogHeader {
+    format_version: u32,
+    codec: String,
+}
+
+// Written as `OpRecord<&NodeSnapshot<Key>, &Key>` and read as `OpRecord<NodeSnapshot<Key>, Key>`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum OpRecord<E, K> {
+    Put(E),
+    Delete(K),
+    /// Generation of a namespace was bumped
+    Generation(SavedNamespace),
+}
+
+/// Record framed as a log section, encoded before the log is locked
+pub(crate) struct EncodedRecord(Result<Vec<u8>, SnapshotError>);
+
+impl EncodedRecord {
+    pub(crate) fn new<T: Serialize>(record: &T) -> Self {
+        EncodedRecord(encode_section(record))
+    }
+}
+
+pub(crate) struct OpLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    fsync: FsyncPolicy,
+    unsynced: u64,
+    // records since the last compaction
+    records: u64,
+    compact_after: Option<u64>,
+    // set when a record could not be written, cleared by compaction
+    failed: bool,
+}
+
+impl OpLog {
+    /// Creates an empty log, replacing the file if it exists
+    pub(crate) fn create(path: &Path, options: &OpLogOptions) -> Result<Self, SnapshotError> {
+        let mut writer = BufWriter::new(File::create(path).map_err(SnapshotError::Io)?);
+        write_log_header(&mut writer)?;
+        let file = writer
+            .into_inner()
+            .map_err(|err| SnapshotError::Io(err.into_error()))?;
+        file.sync_all().map_err(SnapshotError::Io)?;
+
+        Ok(OpLog {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            fsync: options.fsync,
+            unsynced: 0,
+            records: 0,
+            compact_after: options.compact_after,
+            failed: false,
+        })
+    }
+
+    /// Appends a record. Returns true if the log should be compacted now.
+    pub(crate) fn append(&mut self, record: EncodedRecord) -> bool {
+        // once a write failed the log is missing records, and only compaction can fix it
+        if !self.failed && self.write_record(record).is_err() {
+            self.failed = true;
+            return true;
+        }
+        self.records += 1;
+        self.compact_after
+            .is_some_and(|compact_after| self.records.is_multiple_of(compact_after.max(1)))
+    }
+
+    fn write_record(&mut self, record: EncodedRecord) -> Result<(), SnapshotError> {
+        self.writer
+            .write_all(&record.0?)
+            .and_then(|_| self.writer.flush())
+            .map_err(SnapshotError::Io)?;
+        self.unsynced += 1;
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.writer
+                .get_ref()
+                .sync_data()
+                .map_err(SnapshotError::Io)?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// Flushes the log and returns its length, i.e. the offset of the next record
+    pub(crate) fn flushed_len(&mut self) -> Result<u64, SnapshotError> {
+        self.writer.flush().map_err(SnapshotError::Io)?;
+        self.writer
+            .get_ref()
+            .metadata()
+            .map(|metadata| metadata.len())
+            .map_err(SnapshotError::Io)
+    }
+
+    /// Drops records before `offset` (they are in a snapshot now), keeping the ones after it.
+    /// The new log is written next to the old one and renamed over it.
+    pub(crate) fn truncate_front(&mut self, offset: u64) -> Result<(), SnapshotError> {
+        // if writing failed, the tail may end with a partial record, replay skips it
+        let _ = self.writer.flush();
+        let mut old = File::open(&self.path).map_err(SnapshotError::Io)?;
+        old.seek(SeekFrom::Start(offset))
+            .map_err(SnapshotError::Io)?;
+
+        let tmp_path = tmp_path(&self.path);
+        let mut writer = BufWriter::new(File::create(&tmp_path).map_err(SnapshotError::Io)?);
+        write_log_header(&mut writer)?;
+        io::copy(&mut old, &mut writer).map_err(SnapshotError::Io)?;
+        let file = writer
+            .into_inner()
+            .map_err(|err| SnapshotError::Io(err.into_error()))?;
+        file.sync_all().map_err(SnapshotError::Io)?;
+        fs::rename(&tmp_path, &self.path).map_err(SnapshotError::Io)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .map_err(SnapshotError::Io)?;
+        self.writer = BufWriter::new(file);
+        self.unsynced = 0;
+        self.records = 0;
+        self.failed = false;
+        Ok(())
+    }
+}
+
+/// Reads records of the log in order and passes them to `apply`. A record that is cut off or
+/// corrupted ends the log, as it is what a crash in the middle of a write leaves behind.
+/// Returns number of applied records.
+pub(crate) fn replay<T, F>(path: &Path, mut apply: F) -> Result<usize, SnapshotError>
+where
+    T: DeserializeOwned,
+    F: FnMut(T) -> Result<(), SnapshotError>,
+{
+    let mut reader = BufReader::new(File::open(path).map_err(SnapshotError::Io)?);
+    read_log_header(&mut reader)?;
+    let mut applied = 0;
+    loop {
+        let record = match read_section(&mut reader, applied as u64 + 1) {
+            Ok(record) => record,
+            Err(SnapshotError::Truncated | SnapshotError::ChecksumMismatch { .. }) => {
+                return Ok(applied);
+            }
+            Err(err) => return Err(err),
+        };
+        apply(record)?;
+        applied += 1;
+    }
+}
+
+fn write_log_header<W: Write>(writer: &mut W) -> Result<(), SnapshotError> {
+    writer.write_all(&OPLOG_MAGIC).map_err(SnapshotError::Io)?;
+    let header = OpLogHeader {
+        format_version: OPLOG_FORMAT_VERSION,
+        codec: SNAPSHOT_CODEC.to_string(),
+    };
+    write_section(writer, &header)
+}
+
+fn read_log_header<R: io::Read>(reader: &mut R) -> Result<(), SnapshotError> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(read_error)?;
+    if magic != OPLOG_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let header: OpLogHeader = read_section(reader, 0)?;
+    if header.format_version != OPLOG_FORMAT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(header.format_version));
+    }
+    if header.codec != SNAPSHOT_CODEC {
+        return Err(SnapshotError::UnsupportedCodec(header.codec));
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::eviction::{EvictionPolicy, S3Fifo};
+
+/// Tuning knobs of `AlsoCache`. Start from `Options::default()` and override what you need.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Entries heavier than this are rejected with `InsertCacheError::TooLarge`.
+    /// `None` means there is no limit.
+    pub max_entry_weight: Option<u64>,
+
+    /// Entries heavier than the small queue threshold of a shard would flush the whole small
+    /// queue and then get evicted themselves. If set, such entries skip the small queue and
+    /// are inserted directly into the main queue.
+    pub large_entries_bypass_small: bool,
+
+    /// Decides which entries are evicted, S3-FIFO by default. Also sets the sizes of the
+    /// queues of `AlsoCache` shards.
+    pub eviction_policy: Arc<dyn EvictionPolicy>,
+
+    /// W-TinyLFU admission: shards count accesses of keys in a small sketch, and an entry
+    /// leaving the small queue is only promoted to a full main queue if its key was accessed
+    /// more often than the key of the oldest main queue entry. Keeps keys that are only
+    /// requested a couple of times from pushing popular ones out.
+    pub tinylfu_admission: bool,
+
+    /// Frequency of an entry stops growing at this many accesses. Main queue entries are
+    /// reinserted (with the frequency decremented) that many times before they are evicted.
+    pub max_frequency: u8,
+
+    /// If set, shards halve the frequencies of all their entries once per interval, so entries
+    /// that were popular a while ago don't keep their place in the main queue for long.
+    pub frequency_decay_interval: Option<Duration>,
+
+    /// Number of keys each shard counts accesses of, to report the most accessed ones with
+    /// `AlsoCache::hot_keys`. 0 disables tracking. Counts of the top keys are more accurate
+    /// when a few times more keys are tracked than reported.
+    pub hot_keys_tracked: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_entry_weight: None,
+            large_entries_bypass_small: false,
+            eviction_policy: Arc::new(S3Fifo),
+            tinylfu_admission: false,
+            max_frequency: 3,
+            frequency_decay_interval: None,
+            hot_keys_tracked: 0,
+        }
+    }
+}
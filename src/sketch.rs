@@ -0,0 +1,81 @@
+// Count-min sketch estimating how often keys were accessed, used by the TinyLFU admission
+// filter of a shard. Each key maps to one counter in each of the rows, and its estimate
+// is the smallest of them. Counters saturate at 15 (the estimate only has to tell hot keys
+// from cold ones), and are halved after every `AGING_PERIOD * width` increments, so old
+// popularity fades away.
+
+const ROWS: usize = 4;
+const MAX_COUNT: u8 = 15;
+const AGING_PERIOD: u64 = 10;
+const MIN_WIDTH: usize = 1024;
+
+// Odd multipliers to get independent indexes from the key hash
+const SEEDS: [u64; ROWS] = [
+    0x9E37_79B9_7F4A_7C15,
+    0xC2B2_AE3D_27D4_EB4F,
+    0x1656_67B1_9E37_79F9,
+    0x85EB_CA77_C2B2_AE63,
+];
+
+#[derive(Debug)]
+pub(crate) struct FrequencySketch {
+    counters: Vec<u8>,
+    // counters per row, a power of two
+    width: usize,
+    additions: u64,
+}
+
+impl FrequencySketch {
+    pub(crate) fn new(expected_entries: usize) -> Self {
+        let width = expected_entries.max(MIN_WIDTH).next_power_of_two();
+        FrequencySketch {
+            counters: vec![0; width * ROWS],
+            width,
+            additions: 0,
+        }
+    }
+
+    /// Counts an access. `entries` is the number of keys in the shard, the sketch grows
+    /// when they outnumber its counters (losing the counts, so only when they are aged).
+    pub(crate) fn increment(&mut self, hash: u64, entries: usize) {
+        for row in 0..ROWS {
+            let idx = self.index(row, hash);
+            if self.counters[idx] < MAX_COUNT {
+                self.counters[idx] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= AGING_PERIOD * self.width as u64 {
+            if entries > self.width {
+                *self = FrequencySketch::new(entries);
+            } else {
+                self.age();
+            }
+        }
+    }
+
+    pub(crate) fn estimate(&self, hash: u64) -> u8 {
+        (0..ROWS)
+            .map(|row| self.counters[self.index(row, hash)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.counters.fill(0);
+        self.additions = 0;
+    }
+
+    fn age(&mut self) {
+        for counter in &mut self.counters {
+            *counter /= 2;
+        }
+        self.additions = 0;
+    }
+
+    #[inline(always)]
+    fn index(&self, row: usize, hash: u64) -> usize {
+        let mixed = (hash ^ (hash >> 32)).wrapping_mul(SEEDS[row]);
+        row * self.width + (mixed >> (64 - self.width.trailing_zeros())) as usize
+    }
+}
@@ -0,0 +1,270 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use bincode::{
+    config::standard,
+    error::{DecodeError, EncodeError},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::task::JoinHandle;
+
+use crate::cache_shard::ShardError;
+use crate::namespace::SavedNamespace;
+
+// Snapshot file layout:
+// - magic number (8 bytes)
+// - header section (SnapshotHeader)
+// - entries of each shard (Vec<NodeSnapshot<NsKey<Key>>>), one section per shard
+// - ghosts of each shard (Vec<(u64, u64)>: fingerprint and weight, the oldest first),
+//   one section per shard
+//
+// Every section is framed as: payload length (u64 LE), CRC32 of payload (u32 LE), payload.
+// Payloads are encoded with bincode (standard config), the same codec as cache values.
+//
+// Shard of a key depends on the hasher of the cache instance (and the number of shards
+// depends on the machine), so on load entries are routed to shards again. Ghosts are
+// fingerprints of keys computed with fixed seeds, they are restored only if the loading
+// cache computes the same `hasher_probe` (fingerprints did not change between versions).
+
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"ALSOSNAP";
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 5;
+pub const SNAPSHOT_CODEC: &str = "bincode2-standard";
+/// Value hashed into `SnapshotHeader::hasher_probe`
+pub const SNAPSHOT_HASHER_PROBE: &str = "also-cache hasher probe";
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Encode(EncodeError),
+    Decode(DecodeError),
+    /// File is not a snapshot (magic number does not match).
+    BadMagic,
+    /// Snapshot was written by an incompatible version of the format.
+    UnsupportedVersion(u32),
+    /// Snapshot payloads are encoded with an unknown codec.
+    UnsupportedCodec(String),
+    /// Section is corrupted, its checksum does not match.
+    ChecksumMismatch {
+        section: u64,
+    },
+    /// File ends before all sections were read.
+    Truncated,
+    /// Shard was found in an inconsistent state while restoring and was reset.
+    ShardReset(ShardError),
+}
+
+/// Describes the snapshot and the cache it was taken from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub format_version: u32,
+    pub codec: String,
+    /// Type name of the hasher. Informational only, since keys are rehashed on load
+    pub hasher: String,
+    /// Fingerprint of `SNAPSHOT_HASHER_PROBE`: caches that agree on it agree on
+    /// fingerprints of ghosts
+    pub hasher_probe: u64,
+    pub shard_count: u64,
+    /// Number of entry sections following the header
+    pub section_count: u64,
+    /// Number of ghost sections following the entry sections
+    pub ghost_section_count: u64,
+    /// Generations of the namespaces, which are part of the keys of entries
+    pub namespaces: Vec<SavedNamespace>,
+}
+
+/// When the background task of `AlsoCache::spawn_snapshots` writes a snapshot.
+/// A snapshot is written when either condition is met, and skipped if nothing was written
+/// to the cache since the previous one.
+#[derive(Debug, Clone)]
+pub struct SnapshotSchedule {
+    pub path: PathBuf,
+    /// Write a snapshot this long after the previous one
+    pub every: Option<Duration>,
+    /// Write a snapshot after this many updates of the cache
+    pub after_writes: Option<u64>,
+    /// How often the conditions are checked
+    pub poll_interval: Duration,
+}
+
+impl SnapshotSchedule {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SnapshotSchedule {
+            path: path.into(),
+            every: Some(Duration::from_secs(5 * 60)),
+            after_writes: None,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotStatus {
+    pub(crate) written: u64,
+    pub(crate) last_error: Option<SnapshotError>,
+}
+
+/// Handle of the background snapshot task. Dropping it leaves the task running.
+pub struct SnapshotTask {
+    pub(crate) handle: JoinHandle<()>,
+    pub(crate) status: Arc<Mutex<SnapshotStatus>>,
+}
+
+impl SnapshotTask {
+    /// Number of snapshots written so far
+    pub fn snapshots_written(&self) -> u64 {
+        self.lock_status().written
+    }
+
+    /// Returns the error of the last failed snapshot, if it was not taken yet
+    pub fn take_error(&self) -> Option<SnapshotError> {
+        self.lock_status().last_error.take()
+    }
+
+    /// Stops the task. A snapshot that is being written is still completed.
+    pub fn stop(self) {
+        self.handle.abort();
+    }
+
+    fn lock_status(&self) -> std::sync::MutexGuard<'_, SnapshotStatus> {
+        self.status.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub(crate) fn write_header<W: Write>(
+    writer: &mut W,
+    header: &SnapshotHeader,
+) -> Result<(), SnapshotError> {
+    writer
+        .write_all(&SNAPSHOT_MAGIC)
+        .map_err(SnapshotError::Io)?;
+    write_section(writer, header)
+}
+
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> Result<SnapshotHeader, SnapshotError> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(read_error)?;
+    if magic != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let payload = read_payload(reader, 0)?;
+    // the version comes first, and is checked before the rest of the header: headers of
+    // other versions have other fields
+    let format_version: u32 = decode(&payload)?;
+    if format_version != SNAPSHOT_FORMAT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(format_version));
+    }
+    let header: SnapshotHeader = decode(&payload)?;
+    if header.codec != SNAPSHOT_CODEC {
+        return Err(SnapshotError::UnsupportedCodec(header.codec));
+    }
+    Ok(header)
+}
+
+pub(crate) fn write_section<T: Serialize, W: Write>(
+    writer: &mut W,
+    section: &T,
+) -> Result<(), SnapshotError> {
+    writer
+        .write_all(&encode_section(section)?)
+        .map_err(SnapshotError::Io)
+}
+
+/// Section as it is written to the file: length, checksum and payload
+pub(crate) fn encode_section<T: Serialize>(section: &T) -> Result<Vec<u8>, SnapshotError> {
+    let payload =
+        bincode::serde::encode_to_vec(section, standard()).map_err(SnapshotError::Encode)?;
+    let mut framed = Vec::with_capacity(12 + payload.len());
+    framed.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    framed.extend_from_slice(&crc32(&payload).to_le_bytes());
+    framed.extend_from_slice(&payload);
+    Ok(framed)
+}
+
+// `section` is the index of the section in the file (header is 0), used in errors
+pub(crate) fn read_section<T: DeserializeOwned, R: Read>(
+    reader: &mut R,
+    section: u64,
+) -> Result<T, SnapshotError> {
+    decode(&read_payload(reader, section)?)
+}
+
+fn read_payload<R: Read>(reader: &mut R, section: u64) -> Result<Vec<u8>, SnapshotError> {
+    let mut len = [0u8; 8];
+    let mut checksum = [0u8; 4];
+    reader.read_exact(&mut len).map_err(read_error)?;
+    reader.read_exact(&mut checksum).map_err(read_error)?;
+    let len = u64::from_le_bytes(len);
+
+    // do not preallocate using the length, a corrupted one could make us allocate a lot
+    let mut payload = Vec::new();
+    reader
+        .take(len)
+        .read_to_end(&mut payload)
+        .map_err(SnapshotError::Io)?;
+    if (payload.len() as u64) < len {
+        return Err(SnapshotError::Truncated);
+    }
+    if crc32(&payload) != u32::from_le_bytes(checksum) {
+        return Err(SnapshotError::ChecksumMismatch { section });
+    }
+    Ok(payload)
+}
+
+fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, SnapshotError> {
+    bincode::serde::decode_from_slice(payload, standard())
+        .map(|(section, _)| section)
+        .map_err(SnapshotError::Decode)
+}
+
+pub(crate) fn read_error(err: io::Error) -> SnapshotError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => SnapshotError::Truncated,
+        _ => SnapshotError::Io(err),
+    }
+}
+
+/// Path of a temporary file a new version of `path` is written to before renaming. Every
+/// call returns a new path (in the same directory), so concurrent writers of `path` don't
+/// write into each other's files: the last rename wins with a complete file.
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    PathBuf::from(tmp)
+}
+
+// CRC-32 (IEEE), table is computed at compile time
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
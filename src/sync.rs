@@ -1,5 +1,13 @@
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::sync::Mutex;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::convert::identity;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hash};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
 
 use bincode::{
     config::standard,
@@ -7,7 +15,23 @@ use bincode::{
 };
 use serde::{Serialize, de::DeserializeOwned};
 
-use crate::cache_shard::CacheShard;
+use crate::cache_shard::{
+    CacheShard, NodeSnapshot, ShardEntry, ShardError, ShardInfo, ShardReport,
+};
+use crate::ghost::fingerprint;
+use crate::hot_keys::HotKey;
+use crate::namespace::{
+    DEFAULT_NAMESPACE, Namespace, NamespaceId, NamespaceQuota, NamespaceState, NamespaceStats,
+    NsKey, SavedNamespace, namespace_id,
+};
+use crate::oplog::{EncodedRecord, OpLog, OpLogOptions, OpRecord, replay};
+use crate::options::Options;
+use crate::snapshot::{
+    SNAPSHOT_CODEC, SNAPSHOT_FORMAT_VERSION, SNAPSHOT_HASHER_PROBE, SnapshotError, SnapshotHeader,
+    SnapshotSchedule, SnapshotStatus, SnapshotTask, read_header, read_section, tmp_path,
+    write_header, write_section,
+};
+use crate::trace::{TraceOp, TraceOptions, TraceRecorder};
 
 pub const SMALL_THRESHOLD_RATIO: f64 = 0.1;
 pub const MAIN_THRESHOLD_RATIO: f64 = 0.9;
@@ -20,6 +44,12 @@ pub const MIN_SHARD_SIZE: usize = 8192;
 pub enum InsertCacheError {
     Decode(DecodeError),
     Encode(EncodeError),
+    /// Shard was found in an inconsistent state and was reset, all its entries are dropped.
+    ShardReset(ShardError),
+    /// Entry weight exceeds `Options::max_entry_weight`.
+    TooLarge,
+    /// `compare_and_set` failed: key is missing or was updated since it was read.
+    VersionMismatch,
 }
 
 #[derive(Debug)]
@@ -27,9 +57,14 @@ pub enum GetCacheError {
     Decode(DecodeError),
     Encode(EncodeError),
     KeyNotFound,
+    /// Key is cached as missing (see `AlsoCache::insert_absent`).
+    Negative,
+    /// Shard was found in an inconsistent state and was reset, all its entries are dropped.
+    ShardReset(ShardError),
 }
 
 pub trait Weighter<Key>: Default + Clone {
+    #[allow(clippy::ptr_arg)]
     fn weight(&self, key: &Key, val: &Vec<u8>) -> u64;
 }
 
@@ -43,18 +78,155 @@ impl<Key> Weighter<Key> for DefaultWeighter {
 }
 
 pub struct AlsoCache<Key, We, B> {
-    shards: Vec<Mutex<CacheShard<Key, B>>>,
+    shards: Vec<Mutex<CacheShard<NsKey<Key>, B>>>,
     shard_mask: usize,
     weighter: We,
-    hasher: B,
+    options: Options,
+    oplog: Option<OpLogHandle<Key>>,
+    // number of updates, used to schedule snapshots
+    writes: AtomicU64,
+    // opened namespaces by id, including the default one
+    namespaces: RwLock<HashMap<NamespaceId, Arc<NamespaceState>>>,
+    default_namespace: Arc<NamespaceState>,
+    // set while a trace is recorded, so accesses skip the lock otherwise
+    recording: AtomicBool,
+    recorder: RwLock<Option<TraceRecorder>>,
+}
+
+// Records are encoded through function pointers created by `open_oplog`, because methods
+// that update the cache do not require `Key: Serialize`.
+struct OpLogHandle<Key> {
+    log: Mutex<OpLog>,
+    // held while compacting, so compactions don't run concurrently
+    compacting: Mutex<()>,
+    // set when the log should be compacted, updates only set it: compaction writes a whole
+    // snapshot, it is left to the snapshot task or to an explicit `compact_oplog`
+    compact_pending: AtomicBool,
+    snapshot_path: PathBuf,
+    encode_put: fn(&NodeSnapshot<NsKey<Key>>) -> EncodedRecord,
+    encode_delete: fn(&NsKey<Key>) -> EncodedRecord,
+}
+
+impl<Key> OpLogHandle<Key> {
+    fn lock_log(&self) -> MutexGuard<'_, OpLog> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 }
 
 impl<Key: Eq + Hash + Clone, We: Weighter<Key>, B: BuildHasher + Clone> AlsoCache<Key, We, B> {
     #[inline(always)]
-    fn get_shard_index(&self, key: &Key) -> usize {
-        let mut hasher = self.hasher.build_hasher();
-        key.hash(&mut hasher);
-        (hasher.finish() as usize) & self.shard_mask
+    fn get_shard_index<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        // by fingerprint, so ghosts of a key are in its shard in every cache instance
+        (fingerprint(key) as usize) & self.shard_mask
+    }
+
+    /// Locks the shard. If some thread panicked while holding the lock, shard state can't be
+    /// trusted anymore, so the shard is reset and `ShardError::Poisoned` is returned.
+    #[inline(always)]
+    fn lock_shard(
+        &self,
+        shard_idx: usize,
+    ) -> Result<MutexGuard<'_, CacheShard<NsKey<Key>, B>>, ShardError> {
+        self.shards[shard_idx].lock().map_err(|poisoned| {
+            poisoned.into_inner().reset();
+            self.shards[shard_idx].clear_poison();
+            ShardError::Poisoned
+        })
+    }
+
+    /// Runs `update` on the shard of the key. If the operation log is enabled, the new state
+    /// of the key is logged before the shard is unlocked, so records of a key are in the
+    /// same order as its updates. Successful updates are recorded to the access trace as `op`.
+    fn update_shard<R, E>(
+        &self,
+        key: NsKey<Key>,
+        op: Option<TraceOp>,
+        shard_error: fn(ShardError) -> E,
+        update: impl FnOnce(&mut CacheShard<NsKey<Key>, B>, NsKey<Key>) -> Result<R, E>,
+    ) -> Result<R, E> {
+        let shard_idx = self.get_shard_index(&key);
+        let mut shard = self.lock_shard(shard_idx).map_err(shard_error)?;
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        if self.oplog.is_none() && !self.recording.load(Ordering::Relaxed) {
+            return update(&mut shard, key);
+        }
+
+        let logged_key = key.clone();
+        let result = update(&mut shard, key)?;
+        if let Some(op) = op {
+            let size = shard.peek_bytes(&logged_key).map_or(0, Vec::len);
+            self.record(op, &logged_key, size);
+        }
+        let compact = self.log_state(&shard, &logged_key);
+        self.request_compaction(compact);
+        Ok(result)
+    }
+
+    // Records the access to the trace, if the recorder is enabled and samples the key
+    #[inline(always)]
+    fn record<Q: Hash + ?Sized>(&self, op: TraceOp, key: &Q, size: usize) {
+        if !self.recording.load(Ordering::Relaxed) {
+            return;
+        }
+        let recorder = self.recorder.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(recorder) = recorder.as_ref()
+            && let Some(key_hash) = recorder.sample(key)
+        {
+            recorder.record(op, key_hash, size);
+        }
+    }
+
+    #[inline(always)]
+    fn record_get<Q: Hash + ?Sized>(&self, key: &Q, entry: Option<&ShardEntry<'_>>) {
+        match entry {
+            Some(ShardEntry::Data(bytes)) => self.record(TraceOp::GetHit, key, bytes.len()),
+            Some(ShardEntry::Absent) => self.record(TraceOp::GetHit, key, 0),
+            None => self.record(TraceOp::GetMiss, key, 0),
+        }
+    }
+
+    // Appends the current state of the key to the operation log, if it is enabled.
+    // Returns true if the log should be compacted.
+    fn log_state(&self, shard: &CacheShard<NsKey<Key>, B>, key: &NsKey<Key>) -> bool {
+        let Some(oplog) = &self.oplog else {
+            return false;
+        };
+        // encoded before taking the log lock, writers of other shards only wait for the write
+        let record = match shard.export_entry(key) {
+            Some(entry) => (oplog.encode_put)(&entry),
+            None => (oplog.encode_delete)(key),
+        };
+        oplog.lock_log().append(record)
+    }
+
+    fn request_compaction(&self, compact: bool) {
+        if let Some(oplog) = &self.oplog
+            && compact
+        {
+            oplog.compact_pending.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns true if the operation log reached `OpLogOptions::compact_after` records (or
+    /// failed to write one) since the last compaction, and should be compacted with
+    /// `compact_oplog`.
+    pub fn oplog_compaction_pending(&self) -> bool {
+        self.oplog
+            .as_ref()
+            .is_some_and(|oplog| oplog.compact_pending.load(Ordering::Relaxed))
+    }
+
+    /// Same as `lock_shard`, but a poisoned shard is returned (empty) after it was reset.
+    fn lock_shard_or_reset(&self, shard_idx: usize) -> MutexGuard<'_, CacheShard<NsKey<Key>, B>> {
+        self.lock_shard(shard_idx)
+            .unwrap_or_else(|_| self.lock_shard_for_stats(shard_idx))
+    }
+
+    /// Same as `lock_shard`, but for read-only diagnostics, where a poisoned shard is read as is.
+    fn lock_shard_for_stats(&self, shard_idx: usize) -> MutexGuard<'_, CacheShard<NsKey<Key>, B>> {
+        self.shards[shard_idx]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
     }
 
     pub fn with_estimated_count(
@@ -63,127 +235,618 @@ impl<Key: Eq + Hash + Clone, We: Weighter<Key>, B: BuildHasher + Clone> AlsoCach
         weighter: We,
         hasher: B,
     ) -> Self {
-        let shard_count = calculate_shard_count(size);
-        let shard_mask = shard_count - 1;
-        let per_shard_size = size / shard_count;
-        let per_shard_items = estimated_items_count / shard_count;
-
-        let shards = (0..shard_count)
-            .map(|_| {
-                Mutex::new(CacheShard::with_estimated_count(
-                    per_shard_items,
-                    ((per_shard_size as f64 * SMALL_THRESHOLD_RATIO) as u64).max(1),
-                    ((per_shard_size as f64 * MAIN_THRESHOLD_RATIO) as u64).max(1),
-                    ((per_shard_size as f64 * GHOST_THRESHOLD_RATIO) as u64).max(1),
-                    hasher.clone(),
-                ))
-            })
-            .collect();
-
-        AlsoCache {
-            shards,
-            shard_mask,
+        Self::with_options(
+            estimated_items_count,
+            size,
             weighter,
             hasher,
-        }
+            Options::default(),
+        )
     }
 
     pub fn with(size: usize, weighter: We, hasher: B) -> Self {
+        Self::with_options(0, size, weighter, hasher, Options::default())
+    }
+
+    pub fn with_options(
+        estimated_items_count: usize,
+        size: usize,
+        weighter: We,
+        hasher: B,
+        options: Options,
+    ) -> Self {
         let shard_count = calculate_shard_count(size);
         let shard_mask = shard_count - 1;
         let per_shard_size = size / shard_count;
+        let per_shard_items = estimated_items_count / shard_count;
+        let ratios = options.eviction_policy.queue_ratios();
 
         let shards = (0..shard_count)
             .map(|_| {
-                Mutex::new(CacheShard::new(
-                    ((per_shard_size as f64 * SMALL_THRESHOLD_RATIO) as u64).max(1),
-                    ((per_shard_size as f64 * MAIN_THRESHOLD_RATIO) as u64).max(1),
-                    ((per_shard_size as f64 * GHOST_THRESHOLD_RATIO) as u64).max(1),
+                Mutex::new(CacheShard::with_options(
+                    per_shard_items,
+                    ((per_shard_size as f64 * ratios.small) as u64).max(1),
+                    ((per_shard_size as f64 * ratios.main) as u64).max(1),
+                    ((per_shard_size as f64 * ratios.ghost) as u64).max(1),
+                    options.clone(),
                     hasher.clone(),
                 ))
             })
             .collect();
 
+        let default_namespace = Arc::new(NamespaceState::new("", 0));
         AlsoCache {
             shards,
             shard_mask,
             weighter,
-            hasher,
+            options,
+            oplog: None,
+            writes: AtomicU64::new(0),
+            namespaces: RwLock::new(HashMap::from([(
+                DEFAULT_NAMESPACE,
+                Arc::clone(&default_namespace),
+            )])),
+            default_namespace,
+            recording: AtomicBool::new(false),
+            recorder: RwLock::new(None),
         }
     }
 
     #[inline(always)]
     pub fn get<V: DeserializeOwned>(&self, key: &Key) -> Result<V, GetCacheError> {
-        let shard_idx = self.get_shard_index(key);
-        let mut shard = self.shards[shard_idx].lock().unwrap();
-        let bytes = shard.get_bytes(key).ok_or(GetCacheError::KeyNotFound)?;
-        deserialize(bytes).map_err(GetCacheError::Decode)
+        self.get_in(&self.default_namespace, key)
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_in<V: DeserializeOwned>(
+        &self,
+        namespace: &NamespaceState,
+        key: &Key,
+    ) -> Result<V, GetCacheError> {
+        let key = namespace.key_ref(key);
+        let shard_idx = self.get_shard_index(&key);
+        let mut shard = self
+            .lock_shard(shard_idx)
+            .map_err(GetCacheError::ShardReset)?;
+        let entry = shard.get_entry(&key);
+        self.record_get(&key, entry.as_ref());
+        match entry.ok_or(GetCacheError::KeyNotFound)? {
+            ShardEntry::Data(bytes) => deserialize(bytes).map_err(GetCacheError::Decode),
+            ShardEntry::Absent => Err(GetCacheError::Negative),
+        }
+    }
+
+    /// Same as `get`, but also returns the version of the entry, to be passed to
+    /// `compare_and_set`. Version changes every time the entry is updated.
+    pub fn get_with_version<V: DeserializeOwned>(
+        &self,
+        key: &Key,
+    ) -> Result<(V, u64), GetCacheError> {
+        self.get_with_version_in(&self.default_namespace, key)
+    }
+
+    pub(crate) fn get_with_version_in<V: DeserializeOwned>(
+        &self,
+        namespace: &NamespaceState,
+        key: &Key,
+    ) -> Result<(V, u64), GetCacheError> {
+        let key = namespace.key_ref(key);
+        let shard_idx = self.get_shard_index(&key);
+        let mut shard = self
+            .lock_shard(shard_idx)
+            .map_err(GetCacheError::ShardReset)?;
+        let entry = shard.get_entry_with_version(&key);
+        self.record_get(&key, entry.as_ref().map(|(entry, _)| entry));
+        match entry.ok_or(GetCacheError::KeyNotFound)? {
+            (ShardEntry::Data(bytes), version) => deserialize(bytes)
+                .map(|val| (val, version))
+                .map_err(GetCacheError::Decode),
+            (ShardEntry::Absent, _) => Err(GetCacheError::Negative),
+        }
+    }
+
+    /// Updates the entry only if it was not changed since `get_with_version` returned
+    /// `expected_version`. Returns the new version of the entry on success, and
+    /// `InsertCacheError::VersionMismatch` if the key is missing or its version differs.
+    /// Only the value is replaced, tags, cost and expiration time of the entry are kept.
+    pub fn compare_and_set<V: Serialize>(
+        &self,
+        key: Key,
+        expected_version: u64,
+        val: &V,
+    ) -> Result<u64, InsertCacheError> {
+        self.compare_and_set_in(&self.default_namespace, key, expected_version, val)
+    }
+
+    pub(crate) fn compare_and_set_in<V: Serialize>(
+        &self,
+        namespace: &NamespaceState,
+        key: Key,
+        expected_version: u64,
+        val: &V,
+    ) -> Result<u64, InsertCacheError> {
+        let (bytes, weight) = self.encode(&key, val)?;
+        let key = namespace.key(key);
+        self.update_shard(
+            key,
+            Some(TraceOp::Insert),
+            InsertCacheError::ShardReset,
+            |shard, key| {
+                shard
+                    .compare_and_set(key, expected_version, weight, bytes)
+                    .map_err(InsertCacheError::ShardReset)?
+                    .ok_or(InsertCacheError::VersionMismatch)
+            },
+        )
+    }
+
+    /// Returns the cached value, or calls `loader` on a miss and caches what it returns.
+    /// If `loader` returns `None`, the miss itself is cached for `negative_ttl`, so following
+    /// calls return `Ok(None)` without calling `loader` again.
+    pub fn get_or_load<V, F>(
+        &self,
+        key: &Key,
+        negative_ttl: Duration,
+        loader: F,
+    ) -> Result<Option<V>, GetCacheError>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce(&Key) -> Option<V>,
+    {
+        self.get_or_load_in(&self.default_namespace, key, negative_ttl, loader)
+    }
+
+    pub(crate) fn get_or_load_in<V, F>(
+        &self,
+        namespace: &NamespaceState,
+        key: &Key,
+        negative_ttl: Duration,
+        loader: F,
+    ) -> Result<Option<V>, GetCacheError>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce(&Key) -> Option<V>,
+    {
+        match self.get_in(namespace, key) {
+            Ok(val) => return Ok(Some(val)),
+            Err(GetCacheError::Negative) => return Ok(None),
+            Err(GetCacheError::KeyNotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        let loaded = loader(key);
+        let inserted = match &loaded {
+            Some(val) => self.insert_in(namespace, key.clone(), val),
+            None => self.insert_absent_in(namespace, key.clone(), negative_ttl),
+        };
+        match inserted {
+            // value is still valid, it's just not cached
+            Ok(()) | Err(InsertCacheError::TooLarge) => Ok(loaded),
+            Err(InsertCacheError::Encode(err)) => Err(GetCacheError::Encode(err)),
+            Err(InsertCacheError::Decode(err)) => Err(GetCacheError::Decode(err)),
+            Err(InsertCacheError::ShardReset(err)) => Err(GetCacheError::ShardReset(err)),
+            Err(InsertCacheError::VersionMismatch) => {
+                unreachable!("plain inserts are not versioned")
+            }
+        }
     }
 
     #[inline(always)]
     pub fn insert<V: Serialize>(&self, key: Key, val: &V) -> Result<(), InsertCacheError> {
+        self.insert_in(&self.default_namespace, key, val)
+    }
+
+    #[inline(always)]
+    pub(crate) fn insert_in<V: Serialize>(
+        &self,
+        namespace: &NamespaceState,
+        key: Key,
+        val: &V,
+    ) -> Result<(), InsertCacheError> {
+        let (bytes, weight) = self.encode(&key, val)?;
+        let key = namespace.key(key);
+        self.update_shard(
+            key,
+            Some(TraceOp::Insert),
+            InsertCacheError::ShardReset,
+            |shard, key| {
+                shard
+                    .insert_bytes(key, weight, bytes)
+                    .map_err(InsertCacheError::ShardReset)
+            },
+        )
+    }
+
+    /// Inserts or updates an entry with tags, so it can be removed together with other
+    /// entries carrying one of them by `invalidate_tag`. Tags of an updated entry are replaced.
+    pub fn insert_tagged<V: Serialize>(
+        &self,
+        key: Key,
+        val: &V,
+        tags: &[&str],
+    ) -> Result<(), InsertCacheError> {
+        self.insert_tagged_in(&self.default_namespace, key, val, tags)
+    }
+
+    pub(crate) fn insert_tagged_in<V: Serialize>(
+        &self,
+        namespace: &NamespaceState,
+        key: Key,
+        val: &V,
+        tags: &[&str],
+    ) -> Result<(), InsertCacheError> {
+        let (bytes, weight) = self.encode(&key, val)?;
+        let tags = tags.iter().map(|tag| tag.to_string()).collect();
+        let key = namespace.key(key);
+        self.update_shard(
+            key,
+            Some(TraceOp::Insert),
+            InsertCacheError::ShardReset,
+            |shard, key| {
+                shard
+                    .insert_bytes_tagged(key, weight, bytes, tags)
+                    .map_err(InsertCacheError::ShardReset)
+            },
+        )
+    }
+
+    /// Removes all entries carrying `tag` (in every namespace), locking shards one at a time.
+    /// Returns number of removed entries.
+    pub fn invalidate_tag(&self, tag: &str) -> usize {
+        self.remove_from_shards(|shard| shard.invalidate_tag(tag))
+    }
+
+    /// Adds `delta` to the integer counter stored under `key` and returns the new value.
+    /// Missing counters start from 0. Read and update happen under a single shard lock, so
+    /// concurrent increments are never lost. Counters are stored as `i64` and can be read
+    /// with `get::<i64>`; the result saturates at `i64::MIN` / `i64::MAX`.
+    pub fn incr(&self, key: Key, delta: i64) -> Result<i64, InsertCacheError> {
+        self.add_to_counter(&self.default_namespace, key, delta, None)
+    }
+
+    /// Subtracts `delta` from the integer counter stored under `key`, see `incr`.
+    pub fn decr(&self, key: Key, delta: i64) -> Result<i64, InsertCacheError> {
+        self.add_to_counter(&self.default_namespace, key, delta.saturating_neg(), None)
+    }
+
+    /// Same as `incr`, but if the counter is created by this call, it expires after `ttl`.
+    /// Expiration of an existing counter is not extended (fixed window, as for rate limits).
+    pub fn incr_with_ttl(
+        &self,
+        key: Key,
+        delta: i64,
+        ttl: Duration,
+    ) -> Result<i64, InsertCacheError> {
+        self.add_to_counter(&self.default_namespace, key, delta, Some(ttl))
+    }
+
+    pub(crate) fn add_to_counter(
+        &self,
+        namespace: &NamespaceState,
+        key: Key,
+        delta: i64,
+        ttl: Option<Duration>,
+    ) -> Result<i64, InsertCacheError> {
+        let key = namespace.key(key);
+        self.update_shard(
+            key,
+            Some(TraceOp::Insert),
+            InsertCacheError::ShardReset,
+            |shard, key| {
+                let current: i64 = match shard.peek_bytes(&key) {
+                    Some(bytes) => deserialize(bytes).map_err(InsertCacheError::Decode)?,
+                    None => 0,
+                };
+                let new = current.saturating_add(delta);
+                let bytes = serialize(&new).map_err(InsertCacheError::Encode)?;
+                let weight = self.weighter.weight(&key.key, &bytes);
+                shard
+                    .upsert_bytes(key, weight, bytes, ttl)
+                    .map_err(InsertCacheError::ShardReset)?;
+                Ok(new)
+            },
+        )
+    }
+
+    /// Caches a miss for `ttl`: until it expires, `get` returns `GetCacheError::Negative`
+    /// for this key instead of `GetCacheError::KeyNotFound`.
+    pub fn insert_absent(&self, key: Key, ttl: Duration) -> Result<(), InsertCacheError> {
+        self.insert_absent_in(&self.default_namespace, key, ttl)
+    }
+
+    pub(crate) fn insert_absent_in(
+        &self,
+        namespace: &NamespaceState,
+        key: Key,
+        ttl: Duration,
+    ) -> Result<(), InsertCacheError> {
+        // cached misses still take space in the cache, so never let them weigh nothing
+        let weight = self.weighter.weight(&key, &Vec::new()).max(1);
+        let key = namespace.key(key);
+        self.update_shard(
+            key,
+            Some(TraceOp::Insert),
+            InsertCacheError::ShardReset,
+            |shard, key| {
+                shard
+                    .insert_absent(key, weight, ttl)
+                    .map_err(InsertCacheError::ShardReset)
+            },
+        )
+    }
+
+    /// Inserts or updates an entry with a recomputation cost (e.g. loader latency in milliseconds).
+    /// When choosing between entries with equal access frequency, cheaper ones are evicted first.
+    pub fn insert_with_cost<V: Serialize>(
+        &self,
+        key: Key,
+        val: &V,
+        cost: u32,
+    ) -> Result<(), InsertCacheError> {
+        self.insert_with_cost_in(&self.default_namespace, key, val, cost)
+    }
+
+    pub(crate) fn insert_with_cost_in<V: Serialize>(
+        &self,
+        namespace: &NamespaceState,
+        key: Key,
+        val: &V,
+        cost: u32,
+    ) -> Result<(), InsertCacheError> {
+        let (bytes, weight) = self.encode(&key, val)?;
+        let key = namespace.key(key);
+        self.update_shard(
+            key,
+            Some(TraceOp::Insert),
+            InsertCacheError::ShardReset,
+            |shard, key| {
+                shard
+                    .insert_bytes_with_cost(key, weight, bytes, cost)
+                    .map_err(InsertCacheError::ShardReset)
+            },
+        )
+    }
+
+    /// Inserts or updates an entry and pins it, so it is never evicted until `unpin` is called.
+    /// Weight of pinned entries is subtracted from the budget of the rest of the cache.
+    pub fn insert_pinned<V: Serialize>(&self, key: Key, val: &V) -> Result<(), InsertCacheError> {
+        self.insert_pinned_in(&self.default_namespace, key, val)
+    }
+
+    pub(crate) fn insert_pinned_in<V: Serialize>(
+        &self,
+        namespace: &NamespaceState,
+        key: Key,
+        val: &V,
+    ) -> Result<(), InsertCacheError> {
+        let (bytes, weight) = self.encode(&key, val)?;
+        let key = namespace.key(key);
+        self.update_shard(
+            key,
+            Some(TraceOp::Insert),
+            InsertCacheError::ShardReset,
+            |shard, key| {
+                shard
+                    .insert_bytes_pinned(key, weight, bytes)
+                    .map_err(InsertCacheError::ShardReset)
+            },
+        )
+    }
+
+    /// Pins an existing entry. Returns false if the key was not found.
+    pub fn pin(&self, key: &Key) -> bool {
+        self.pin_in(&self.default_namespace, key)
+    }
+
+    pub(crate) fn pin_in(&self, namespace: &NamespaceState, key: &Key) -> bool {
+        let key = namespace.key(key.clone());
+        self.update_shard(key, None, identity, |shard, key| Ok(shard.pin(&key)))
+            .unwrap_or(false)
+    }
+
+    /// Makes a pinned entry evictable again. Returns false if the key was not found or not pinned.
+    pub fn unpin(&self, key: &Key) -> bool {
+        self.unpin_in(&self.default_namespace, key)
+    }
+
+    pub(crate) fn unpin_in(&self, namespace: &NamespaceState, key: &Key) -> bool {
+        let key = namespace.key(key.clone());
+        self.update_shard(key, None, identity, |shard, key| Ok(shard.unpin(&key)))
+            .unwrap_or(false)
+    }
+
+    // Serializes the value and checks its weight against the limit
+    #[inline(always)]
+    fn encode<V: Serialize>(&self, key: &Key, val: &V) -> Result<(Vec<u8>, u64), InsertCacheError> {
         let bytes = serialize(val).map_err(InsertCacheError::Encode)?;
-        let weight = self.weighter.weight(&key, &bytes);
-        let shard_idx = self.get_shard_index(&key);
-        let mut shard = self.shards[shard_idx].lock().unwrap();
-        shard.insert_bytes(key, weight, bytes);
-        Ok(())
+        let weight = self.weighter.weight(key, &bytes);
+        if self
+            .options
+            .max_entry_weight
+            .is_some_and(|max_weight| weight > max_weight)
+        {
+            return Err(InsertCacheError::TooLarge);
+        }
+        Ok((bytes, weight))
     }
 
     #[inline(always)]
     pub fn delete(&self, key: &Key) -> bool {
-        let shard_idx = self.get_shard_index(key);
-        let mut shard = self.shards[shard_idx].lock().unwrap();
-        shard.delete(key)
+        self.delete_in(&self.default_namespace, key)
     }
 
-    pub fn print_queues(&self, limit: usize) {
-        for (i, shard) in self.shards.iter().enumerate() {
-            println!("Shard {}:", i);
-            let shard = shard.lock().unwrap();
-            shard.print_queues(limit);
+    #[inline(always)]
+    pub(crate) fn delete_in(&self, namespace: &NamespaceState, key: &Key) -> bool {
+        let key = namespace.key(key.clone());
+        self.update_shard(key, Some(TraceOp::Delete), identity, |shard, key| {
+            Ok(shard.delete(&key))
+        })
+        // shard was just reset, so the key is gone anyway
+        .unwrap_or(false)
+    }
+
+    /// Returns a handle to the namespace with the given name. Namespaces have separate keys,
+    /// but share the queues and the capacity of the cache, so a busy namespace can use the
+    /// space an idle one doesn't need. Values of different types can be stored in each.
+    ///
+    /// # Panics
+    ///
+    /// If the id of the name collides with the id of another namespace (see `namespace_id`).
+    pub fn namespace(&self, name: &str) -> Namespace<'_, Key, We, B> {
+        Namespace::new(self, self.register_namespace(name))
+    }
+
+    /// Makes every entry of the namespace a miss at once, see `Namespace::bump_generation`.
+    /// The empty name is the default namespace. Returns the new generation, or None if no
+    /// namespace with this name was opened.
+    pub fn bump_generation(&self, namespace: &str) -> Option<u64> {
+        let state = self
+            .read_namespaces()
+            .get(&namespace_id(namespace))
+            .filter(|state| state.name == namespace)
+            .cloned()?;
+        Some(self.bump_generation_in(&state))
+    }
+
+    /// Names of the namespaces opened with `namespace` (and the default one, named "").
+    pub fn namespace_names(&self) -> Vec<String> {
+        self.read_namespaces()
+            .values()
+            .map(|state| state.name.clone())
+            .collect()
+    }
+
+    fn read_namespaces(&self) -> RwLockReadGuard<'_, HashMap<NamespaceId, Arc<NamespaceState>>> {
+        self.namespaces
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn register_namespace(&self, name: &str) -> Arc<NamespaceState> {
+        let id = namespace_id(name);
+        // the read guard must be released before taking the write lock
+        let found = self.read_namespaces().get(&id).cloned();
+        let registered = match found {
+            Some(state) => state,
+            None => {
+                let mut namespaces = self
+                    .namespaces
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner);
+                Arc::clone(
+                    namespaces
+                        .entry(id)
+                        .or_insert_with(|| Arc::new(NamespaceState::new(name, 0))),
+                )
+            }
+        };
+        assert!(
+            registered.name == name,
+            "namespaces {:?} and {:?} have the same id",
+            registered.name,
+            name
+        );
+        registered
+    }
+
+    // Brings generation of a namespace up to the saved one, when restoring
+    fn restore_namespace(&self, saved: &SavedNamespace) {
+        self.register_namespace(&saved.name)
+            .restore_generation(saved.generation);
+    }
+
+    pub(crate) fn bump_generation_in(&self, namespace: &NamespaceState) -> u64 {
+        let generation = namespace.bump_generation();
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        if let Some(oplog) = &self.oplog {
+            let saved = SavedNamespace {
+                name: namespace.name.clone(),
+                generation,
+            };
+            // this variant is encoded the same way whatever the types of the others are
+            let record = EncodedRecord::new(&OpRecord::<(), ()>::Generation(saved));
+            let compact = oplog.lock_log().append(record);
+            self.request_compaction(compact);
         }
+        generation
     }
 
-    pub fn print_shard_utilization(&self) {
-        let mut total_small = 0;
-        let mut total_main = 0;
-        let mut total_ghost = 0;
-        let mut non_empty_shards = 0;
+    // Removes all entries of the namespace, returns number of removed entries
+    pub(crate) fn clear_in(&self, namespace: &NamespaceState) -> usize {
+        self.remove_from_shards(|shard| shard.retain(|key| key.namespace != namespace.id))
+    }
 
-        println!("=== Shard Utilization Analysis ===");
-        for (i, shard) in self.shards.iter().enumerate() {
-            let shard = shard.lock().unwrap();
-            let small_count = shard.get_small_size();
-            let main_count = shard.get_main_size();
-            let ghost_count = shard.get_ghost_size();
-
-            if small_count + main_count + ghost_count > 0 {
-                println!(
-                    "Shard {}: Small={}, Main={}, Ghost={}",
-                    i, small_count, main_count, ghost_count
-                );
-                non_empty_shards += 1;
+    // Runs `remove` on every shard and logs removal of the keys it returns
+    fn remove_from_shards(
+        &self,
+        remove: impl Fn(&mut CacheShard<NsKey<Key>, B>) -> Vec<NsKey<Key>>,
+    ) -> usize {
+        let mut removed = 0;
+        let mut compact = false;
+        for shard_idx in 0..self.shards.len() {
+            let Ok(mut shard) = self.lock_shard(shard_idx) else {
+                // shard was just reset, nothing to remove
+                continue;
+            };
+            let removed_keys = remove(&mut shard);
+            removed += removed_keys.len();
+            for key in &removed_keys {
+                compact |= self.log_state(&shard, key);
             }
+        }
+        self.request_compaction(compact);
+        removed
+    }
 
-            total_small += small_count;
-            total_main += main_count;
-            total_ghost += ghost_count;
+    // Splits the quota between shards and applies it. Returns the first error, but the quota
+    // is set in every shard anyway
+    pub(crate) fn set_quota_in(
+        &self,
+        namespace: &NamespaceState,
+        quota: Option<NamespaceQuota>,
+    ) -> Result<(), ShardError> {
+        let shard_count = self.shards.len() as u64;
+        let shard_quota = quota.map(|quota| NamespaceQuota {
+            max_weight: quota
+                .max_weight
+                .map(|max_weight| max_weight.div_ceil(shard_count)),
+            min_weight: quota.min_weight / shard_count,
+        });
+        let mut result = Ok(());
+        for shard_idx in 0..self.shards.len() {
+            // shard that was reset is consistent again, so the quota is still set
+            let mut shard = match self.lock_shard(shard_idx) {
+                Ok(shard) => shard,
+                Err(err) => {
+                    result = result.and(Err(err));
+                    self.lock_shard_for_stats(shard_idx)
+                }
+            };
+            let set = shard.set_quota(namespace.id, shard_quota);
+            result = result.and(set);
         }
+        result
+    }
 
-        println!(
-            "Total across {} shards: Small={}, Main={}, Ghost={}",
-            self.shards.len(),
-            total_small,
-            total_main,
-            total_ghost
-        );
-        println!(
-            "Non-empty shards: {}/{}",
-            non_empty_shards,
-            self.shards.len()
-        );
-        println!("=== End Utilization Analysis ===");
+    pub(crate) fn stats_in(&self, namespace: &NamespaceState) -> NamespaceStats {
+        let mut stats = NamespaceStats::default();
+        let generation = namespace.generation();
+        for shard_idx in 0..self.shards.len() {
+            let shard = self.lock_shard_for_stats(shard_idx);
+            let (entries, weight) =
+                shard.usage(|key| key.namespace == namespace.id && key.generation == generation);
+            stats.entries += entries;
+            stats.weight += weight;
+        }
+        stats
+    }
+
+    /// Describes every shard, in the order of shards, see `CacheShard::inspect`.
+    /// Keys include their namespace id and generation.
+    pub fn inspect(&self, keys_per_queue: usize) -> Vec<ShardInfo<NsKey<Key>>> {
+        (0..self.shards.len())
+            .map(|i| self.lock_shard_for_stats(i).inspect(keys_per_queue))
+            .collect()
     }
 
     pub fn get_utilization_stats(&self) -> (u64, u64, u64, usize) {
@@ -192,8 +855,8 @@ impl<Key: Eq + Hash + Clone, We: Weighter<Key>, B: BuildHasher + Clone> AlsoCach
         let mut total_ghost = 0;
         let mut non_empty_shards = 0;
 
-        for shard in &self.shards {
-            let shard = shard.lock().unwrap();
+        for i in 0..self.shards.len() {
+            let shard = self.lock_shard_for_stats(i);
             total_small += shard.get_small_size();
             total_main += shard.get_main_size();
             total_ghost += shard.get_ghost_size();
@@ -205,6 +868,355 @@ impl<Key: Eq + Hash + Clone, We: Weighter<Key>, B: BuildHasher + Clone> AlsoCach
 
         (total_small, total_main, total_ghost, non_empty_shards)
     }
+
+    /// Starts recording sampled `get`, `insert` and `delete` calls (and other updates of
+    /// entries) to a trace file, which can be replayed with the `simulate` binary.
+    /// A recording that was already running is stopped first.
+    pub fn start_recording(&self, options: TraceOptions) -> Result<(), SnapshotError> {
+        let recorder = TraceRecorder::create(&options)?;
+        let mut current = self
+            .recorder
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(previous) = current.take() {
+            self.recording.store(false, Ordering::Relaxed);
+            previous.finish()?;
+        }
+        *current = Some(recorder);
+        self.recording.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stops recording and flushes the trace. Returns the number of recorded accesses,
+    /// or the first error that occurred while writing them.
+    pub fn stop_recording(&self) -> Result<u64, SnapshotError> {
+        let mut current = self
+            .recorder
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.recording.store(false, Ordering::Relaxed);
+        current.take().map_or(Ok(0), TraceRecorder::finish)
+    }
+
+    /// Number of entries evicted so far across all shards, see `CacheShard::get_evictions`.
+    pub fn get_eviction_count(&self) -> u64 {
+        (0..self.shards.len())
+            .map(|i| self.lock_shard_for_stats(i).get_evictions())
+            .sum()
+    }
+
+    /// Checks internal invariants of every shard, see `CacheShard::validate`.
+    /// Returns one report per shard, in the order of shards.
+    pub fn validate(&self) -> Vec<ShardReport> {
+        (0..self.shards.len())
+            .map(|i| self.lock_shard_for_stats(i).validate())
+            .collect()
+    }
+
+    /// Up to `k` most accessed keys of all namespaces, the most accessed first. Requires
+    /// `Options::hot_keys_tracked`, see `CacheShard::hot_keys` for what is counted.
+    /// Entries of older generations of a namespace are skipped.
+    ///
+    /// Misses are counted, but the tracker keeps only hashes, and keys are looked up among
+    /// the entries of the cache: a key that keeps missing (and never gets inserted) is not
+    /// reported, even if it is the hottest one. Cache misses with `insert_absent` to see
+    /// such keys, a cached miss is an entry like any other.
+    pub fn hot_keys(&self, k: usize) -> Vec<HotKey<Key>> {
+        let namespaces = self.read_namespaces();
+        let is_current = |key: &NsKey<Key>| {
+            namespaces
+                .get(&key.namespace)
+                .is_some_and(|state| state.generation() == key.generation)
+        };
+        let mut hot_keys = Vec::new();
+        for shard_idx in 0..self.shards.len() {
+            let shard = self.lock_shard_for_stats(shard_idx);
+            for (key, count, error) in shard.hot_keys(k, is_current) {
+                hot_keys.push(HotKey {
+                    namespace: namespaces[&key.namespace].name.clone(),
+                    key: key.key,
+                    shard: shard_idx,
+                    count,
+                    error,
+                });
+            }
+        }
+        hot_keys.sort_unstable_by_key(|hot_key| Reverse(hot_key.count));
+        hot_keys.truncate(k);
+        hot_keys
+    }
+}
+
+impl<Key, We, B> AlsoCache<Key, We, B>
+where
+    Key: Eq + Hash + Clone + Serialize + DeserializeOwned,
+    We: Weighter<Key>,
+    B: BuildHasher + Clone,
+{
+    /// Writes all entries and ghosts of the cache to a file, so a restarted node can load
+    /// them with `load_snapshot`.
+    /// Shards are locked and copied one at a time. The snapshot is written to a temporary
+    /// file first and renamed over `path`, so a crash never leaves a partial snapshot.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let path = path.as_ref();
+        let tmp_path = tmp_path(path);
+        let saved = File::create(&tmp_path)
+            .map_err(SnapshotError::Io)
+            .and_then(|file| self.write_snapshot(file))
+            .and_then(|_| fs::rename(&tmp_path, path).map_err(SnapshotError::Io));
+        if saved.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        saved
+    }
+
+    fn write_snapshot(&self, file: File) -> Result<(), SnapshotError> {
+        let mut writer = BufWriter::new(file);
+        let header = SnapshotHeader {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            codec: SNAPSHOT_CODEC.to_string(),
+            hasher: std::any::type_name::<B>().to_string(),
+            hasher_probe: fingerprint(SNAPSHOT_HASHER_PROBE),
+            shard_count: self.shards.len() as u64,
+            section_count: self.shards.len() as u64,
+            ghost_section_count: self.shards.len() as u64,
+            namespaces: self
+                .read_namespaces()
+                .values()
+                .map(|state| SavedNamespace {
+                    name: state.name.clone(),
+                    generation: state.generation(),
+                })
+                .collect(),
+        };
+        write_header(&mut writer, &header)?;
+        // a poisoned shard may be in the middle of an update, it is saved empty
+        for shard_idx in 0..self.shards.len() {
+            let entries = self.lock_shard_or_reset(shard_idx).export();
+            write_section(&mut writer, &entries)?;
+        }
+        for shard_idx in 0..self.shards.len() {
+            let ghosts = self.lock_shard_or_reset(shard_idx).export_ghosts();
+            write_section(&mut writer, &ghosts)?;
+        }
+        let file = writer
+            .into_inner()
+            .map_err(|err| SnapshotError::Io(err.into_error()))?;
+        file.sync_all().map_err(SnapshotError::Io)
+    }
+
+    /// Restores entries saved with `save_snapshot`, keeping their queues and frequencies.
+    /// Keys already present in the cache are kept as is. Returns number of restored entries,
+    /// which includes entries evicted right away if the cache is smaller than the one the
+    /// snapshot was taken from.
+    ///
+    /// Ghosts are restored too, unless the snapshot was written by a version of the crate
+    /// that computed fingerprints of keys differently.
+    ///
+    /// The whole file is verified before anything is restored, so a truncated or
+    /// corrupted snapshot is rejected and the cache is left untouched.
+    pub fn load_snapshot(&self, path: impl AsRef<Path>) -> Result<usize, SnapshotError> {
+        let file = File::open(path).map_err(SnapshotError::Io)?;
+        let mut reader = BufReader::new(file);
+        let header = read_header(&mut reader)?;
+        for saved in &header.namespaces {
+            self.restore_namespace(saved);
+        }
+
+        // number of shards depends on the machine, so every entry is routed to its shard again
+        let mut per_shard: Vec<Vec<NodeSnapshot<NsKey<Key>>>> =
+            (0..self.shards.len()).map(|_| Vec::new()).collect();
+        for section in 1..=header.section_count {
+            let entries: Vec<NodeSnapshot<NsKey<Key>>> = read_section(&mut reader, section)?;
+            for entry in entries {
+                per_shard[self.get_shard_index(&entry.key)].push(entry);
+            }
+        }
+        // fingerprint of a ghost also picks the shard of its key
+        let same_hasher = header.hasher_probe == fingerprint(SNAPSHOT_HASHER_PROBE);
+        let mut ghosts_per_shard: Vec<Vec<(u64, u64)>> =
+            (0..self.shards.len()).map(|_| Vec::new()).collect();
+        for section in 1..=header.ghost_section_count {
+            let ghosts: Vec<(u64, u64)> =
+                read_section(&mut reader, header.section_count + section)?;
+            if same_hasher {
+                for (fingerprint, weight) in ghosts {
+                    ghosts_per_shard[fingerprint as usize & self.shard_mask]
+                        .push((fingerprint, weight));
+                }
+            }
+        }
+
+        let mut restored = 0;
+        for (shard_idx, (entries, ghosts)) in
+            per_shard.into_iter().zip(ghosts_per_shard).enumerate()
+        {
+            let mut shard = self
+                .lock_shard(shard_idx)
+                .map_err(SnapshotError::ShardReset)?;
+            // entries forget ghosts of their keys, so ghosts go first
+            for (fingerprint, weight) in ghosts {
+                shard.restore_ghost(fingerprint, weight);
+            }
+            for entry in entries {
+                if shard.restore(entry) {
+                    restored += 1;
+                }
+            }
+            // the cache may be smaller than the one the snapshot was taken from
+            shard.evict_if_needed().map_err(SnapshotError::ShardReset)?;
+        }
+        Ok(restored)
+    }
+
+    /// Recovers the cache from the snapshot and the operation log in `options` (if they
+    /// exist), and then starts logging every update of the cache (inserts, deletes, pins),
+    /// so the state can be recovered after a crash. Returns number of replayed log records.
+    ///
+    /// Recovered state is compacted into a new snapshot right away, and the log starts empty.
+    pub fn open_oplog(&mut self, options: OpLogOptions) -> Result<usize, SnapshotError> {
+        if options.snapshot_path.exists() {
+            self.load_snapshot(&options.snapshot_path)?;
+        }
+        let replayed = if options.log_path.exists() {
+            replay(&options.log_path, |record| self.apply_record(record))?
+        } else {
+            0
+        };
+        for shard_idx in 0..self.shards.len() {
+            self.lock_shard(shard_idx)
+                .map_err(SnapshotError::ShardReset)?
+                .evict_if_needed()
+                .map_err(SnapshotError::ShardReset)?;
+        }
+
+        // the log may end with a partial record, start from a clean one
+        self.save_snapshot(&options.snapshot_path)?;
+        let log = OpLog::create(&options.log_path, &options)?;
+        self.oplog = Some(OpLogHandle {
+            log: Mutex::new(log),
+            compacting: Mutex::new(()),
+            compact_pending: AtomicBool::new(false),
+            snapshot_path: options.snapshot_path,
+            encode_put: |entry| EncodedRecord::new(&OpRecord::<_, &NsKey<Key>>::Put(entry)),
+            encode_delete: |key| {
+                EncodedRecord::new(&OpRecord::<&NodeSnapshot<NsKey<Key>>, _>::Delete(key))
+            },
+        });
+        Ok(replayed)
+    }
+
+    /// Writes a new snapshot and drops the records it contains from the operation log.
+    /// The background task of `spawn_snapshots` calls it when `oplog_compaction_pending`,
+    /// without it the application has to. Does nothing if the log is not enabled.
+    pub fn compact_oplog(&self) -> Result<(), SnapshotError> {
+        let Some(oplog) = &self.oplog else {
+            return Ok(());
+        };
+        let _compacting = oplog
+            .compacting
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        oplog.compact_pending.store(false, Ordering::Relaxed);
+
+        // every record before the offset is applied to the shards by now, so it is in the
+        // snapshot. Records after it may be too, which is fine, since replaying is idempotent
+        let offset = oplog.lock_log().flushed_len();
+        let compacted = offset.and_then(|offset| {
+            self.save_snapshot(&oplog.snapshot_path)?;
+            oplog.lock_log().truncate_front(offset)
+        });
+        if compacted.is_err() {
+            // retried by the next compaction
+            oplog.compact_pending.store(true, Ordering::Relaxed);
+        }
+        compacted
+    }
+
+    /// Starts a tokio task that writes snapshots to `schedule.path` in the background
+    /// (see `save_snapshot`), and compacts the operation log when it is due (see
+    /// `compact_oplog`). Snapshots are written on the blocking thread pool, so it must be
+    /// called from within a tokio runtime.
+    pub fn spawn_snapshots(self: &Arc<Self>, schedule: SnapshotSchedule) -> SnapshotTask
+    where
+        Key: Send + Sync + 'static,
+        We: Send + Sync + 'static,
+        B: Send + Sync + 'static,
+    {
+        let cache = Arc::clone(self);
+        let status = Arc::new(Mutex::new(SnapshotStatus::default()));
+        let task_status = Arc::clone(&status);
+        let mut last_at = Instant::now();
+        let mut last_writes = cache.writes.load(Ordering::Relaxed);
+        let handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(schedule.poll_interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                if cache.oplog_compaction_pending() {
+                    let compact_cache = Arc::clone(&cache);
+                    let result =
+                        tokio::task::spawn_blocking(move || compact_cache.compact_oplog()).await;
+                    if let Some(err) = blocking_error(result) {
+                        task_status
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .last_error = Some(err);
+                    }
+                }
+
+                let writes = cache.writes.load(Ordering::Relaxed);
+                let due = schedule
+                    .every
+                    .is_some_and(|every| last_at.elapsed() >= every)
+                    || schedule
+                        .after_writes
+                        .is_some_and(|after_writes| writes - last_writes >= after_writes);
+                if !due || writes == last_writes {
+                    continue;
+                }
+
+                let snapshot_cache = Arc::clone(&cache);
+                let path = schedule.path.clone();
+                let result =
+                    tokio::task::spawn_blocking(move || snapshot_cache.save_snapshot(&path)).await;
+                last_at = Instant::now();
+                last_writes = writes;
+
+                let mut status = task_status.lock().unwrap_or_else(PoisonError::into_inner);
+                match blocking_error(result) {
+                    None => status.written += 1,
+                    Some(err) => status.last_error = Some(err),
+                }
+            }
+        });
+        SnapshotTask { handle, status }
+    }
+
+    fn apply_record(
+        &self,
+        record: OpRecord<NodeSnapshot<NsKey<Key>>, NsKey<Key>>,
+    ) -> Result<(), SnapshotError> {
+        let key = match &record {
+            OpRecord::Put(entry) => &entry.key,
+            OpRecord::Delete(key) => key,
+            OpRecord::Generation(saved) => {
+                self.restore_namespace(saved);
+                return Ok(());
+            }
+        };
+        let mut shard = self
+            .lock_shard(self.get_shard_index(key))
+            .map_err(SnapshotError::ShardReset)?;
+        match record {
+            OpRecord::Put(entry) => shard.replace(entry),
+            OpRecord::Delete(key) => {
+                shard.purge(&key);
+            }
+            OpRecord::Generation(_) => {}
+        }
+        Ok(())
+    }
 }
 
 impl<Key: Eq + Hash + Clone> AlsoCache<Key, DefaultWeighter, ahash::RandomState> {
@@ -233,6 +1245,17 @@ fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError> {
     bincode::serde::decode_from_slice::<T, _>(bytes, standard()).map(|(res, _)| res)
 }
 
+// Error of a snapshot or compaction run by the background task on the blocking pool
+fn blocking_error(
+    result: Result<Result<(), SnapshotError>, tokio::task::JoinError>,
+) -> Option<SnapshotError> {
+    match result {
+        Ok(result) => result.err(),
+        // it panicked, the shard it held is reset on the next access
+        Err(err) => Some(SnapshotError::Io(std::io::Error::other(err))),
+    }
+}
+
 fn calculate_shard_count(total_size: usize) -> usize {
     let cpu_count = std::thread::available_parallelism()
         .map(|n| n.get())
@@ -0,0 +1,62 @@
+use std::collections::{HashMap, HashSet};
+
+// Secondary index of entry tags in a shard. Most entries have no tags, so tags of a node are
+// kept in a side table instead of the node itself.
+#[derive(Debug, Default)]
+pub(crate) struct TagIndex {
+    nodes_by_tag: HashMap<String, HashSet<u32>>,
+    tags_by_node: HashMap<u32, Vec<String>>,
+}
+
+impl TagIndex {
+    // Replaces tags of the node
+    pub(crate) fn set(&mut self, idx: u32, mut tags: Vec<String>) {
+        self.remove(idx);
+        if tags.is_empty() {
+            return;
+        }
+        tags.sort_unstable();
+        tags.dedup();
+        for tag in &tags {
+            self.nodes_by_tag
+                .entry(tag.clone())
+                .or_default()
+                .insert(idx);
+        }
+        self.tags_by_node.insert(idx, tags);
+    }
+
+    pub(crate) fn remove(&mut self, idx: u32) {
+        if self.tags_by_node.is_empty() {
+            return;
+        }
+        let Some(tags) = self.tags_by_node.remove(&idx) else {
+            return;
+        };
+        for tag in tags {
+            if let Some(nodes) = self.nodes_by_tag.get_mut(&tag) {
+                nodes.remove(&idx);
+                if nodes.is_empty() {
+                    self.nodes_by_tag.remove(&tag);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn tags(&self, idx: u32) -> &[String] {
+        self.tags_by_node.get(&idx).map_or(&[], Vec::as_slice)
+    }
+
+    // Copied, since nodes are usually removed from the index while going through them
+    pub(crate) fn nodes(&self, tag: &str) -> Vec<u32> {
+        self.nodes_by_tag
+            .get(tag)
+            .map(|nodes| nodes.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.nodes_by_tag.clear();
+        self.tags_by_node.clear();
+    }
+}
@@ -0,0 +1,231 @@
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use bincode::error::{AllowedEnumVariants, DecodeError};
+
+use crate::snapshot::{SnapshotError, read_error};
+
+// Access trace file layout (all integers are little endian):
+// - magic number (8 bytes)
+// - format version (u32), sampling rate (u64), start of recording in unix millis (u64)
+// - fixed size records: op (u8), micros since the start (u64), key hash (u64), size (u32)
+//
+// Keys are hashed with fixed seeds, so hashes of a key are the same in every process.
+// A key is either sampled or not, so the trace has all accesses of the sampled keys.
+
+pub const TRACE_MAGIC: [u8; 8] = *b"ALSOTRCE";
+pub const TRACE_FORMAT_VERSION: u32 = 1;
+const RECORD_LEN: usize = 21;
+
+/// Configuration of the access trace recorder, see `AlsoCache::start_recording`.
+#[derive(Debug, Clone)]
+pub struct TraceOptions {
+    pub path: PathBuf,
+    /// Record accesses of one key in this many (by key hash), 1 records every access
+    pub sample_one_in: u64,
+    /// Stop recording after this many records
+    pub max_records: Option<u64>,
+}
+
+impl TraceOptions {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TraceOptions {
+            path: path.into(),
+            sample_one_in: 100,
+            max_records: Some(10_000_000),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+    /// `get` found the entry (or a cached miss)
+    GetHit,
+    GetMiss,
+    /// Any call that stores an entry: `insert`, `incr`, `compare_and_set`, ...
+    Insert,
+    Delete,
+}
+
+impl TraceOp {
+    fn from_u8(op: u8) -> Option<Self> {
+        match op {
+            0 => Some(TraceOp::GetHit),
+            1 => Some(TraceOp::GetMiss),
+            2 => Some(TraceOp::Insert),
+            3 => Some(TraceOp::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub op: TraceOp,
+    /// Time since the start of recording
+    pub micros: u64,
+    pub key_hash: u64,
+    /// Encoded size of the value, 0 for misses, deletes and cached misses
+    pub size: u32,
+}
+
+/// Recorded access trace, see `read_trace`.
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub sample_one_in: u64,
+    /// Start of recording, in milliseconds since the unix epoch
+    pub started_at: u64,
+    pub records: Vec<TraceRecord>,
+}
+
+struct TraceWriter {
+    writer: BufWriter<File>,
+    // first write error, recording stops after it
+    error: Option<io::Error>,
+}
+
+pub(crate) struct TraceRecorder {
+    writer: Mutex<TraceWriter>,
+    hasher: ahash::RandomState,
+    sample_one_in: u64,
+    max_records: u64,
+    records: AtomicU64,
+    started: Instant,
+}
+
+impl TraceRecorder {
+    pub(crate) fn create(options: &TraceOptions) -> Result<Self, SnapshotError> {
+        let mut writer = BufWriter::new(File::create(&options.path).map_err(SnapshotError::Io)?);
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        let sample_one_in = options.sample_one_in.max(1);
+        writer
+            .write_all(&TRACE_MAGIC)
+            .and_then(|_| writer.write_all(&TRACE_FORMAT_VERSION.to_le_bytes()))
+            .and_then(|_| writer.write_all(&sample_one_in.to_le_bytes()))
+            .and_then(|_| writer.write_all(&started_at.to_le_bytes()))
+            .map_err(SnapshotError::Io)?;
+        Ok(TraceRecorder {
+            writer: Mutex::new(TraceWriter {
+                writer,
+                error: None,
+            }),
+            hasher: ahash::RandomState::with_seeds(
+                0x2d35_8dcc_aa6c_78a5,
+                0x8bb8_4b93_962e_acc9,
+                0x4b33_a62e_d433_d4a3,
+                0x4d5a_2da5_1de1_aa47,
+            ),
+            sample_one_in,
+            max_records: options.max_records.unwrap_or(u64::MAX),
+            records: AtomicU64::new(0),
+            started: Instant::now(),
+        })
+    }
+
+    /// Returns the hash of the key if its accesses are recorded
+    #[inline(always)]
+    pub(crate) fn sample<Q: Hash + ?Sized>(&self, key: &Q) -> Option<u64> {
+        let key_hash = self.hasher.hash_one(key);
+        key_hash
+            .is_multiple_of(self.sample_one_in)
+            .then_some(key_hash)
+    }
+
+    pub(crate) fn record(&self, op: TraceOp, key_hash: u64, size: usize) {
+        if self.records.load(Ordering::Relaxed) >= self.max_records {
+            return;
+        }
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        // checked again under the lock, so the limit is exact
+        let records = self.records.load(Ordering::Relaxed);
+        if records >= self.max_records || writer.error.is_some() {
+            return;
+        }
+        let mut record = [0u8; RECORD_LEN];
+        record[0] = op as u8;
+        record[1..9].copy_from_slice(&(self.started.elapsed().as_micros() as u64).to_le_bytes());
+        record[9..17].copy_from_slice(&key_hash.to_le_bytes());
+        record[17..21].copy_from_slice(&(size.min(u32::MAX as usize) as u32).to_le_bytes());
+        let mut written = writer.writer.write_all(&record);
+        if written.is_ok() && records + 1 == self.max_records {
+            written = writer.writer.flush();
+        }
+        match written {
+            Ok(()) => self.records.store(records + 1, Ordering::Relaxed),
+            Err(err) => writer.error = Some(err),
+        }
+    }
+
+    /// Flushes the trace, returns the number of records or the first write error
+    pub(crate) fn finish(self) -> Result<u64, SnapshotError> {
+        let mut writer = self
+            .writer
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(err) = writer.error.take() {
+            return Err(SnapshotError::Io(err));
+        }
+        writer
+            .writer
+            .flush()
+            .and_then(|_| writer.writer.get_ref().sync_all())
+            .map_err(SnapshotError::Io)?;
+        Ok(self.records.into_inner())
+    }
+}
+
+/// Reads a trace written by `AlsoCache::start_recording`. A partial record at the end
+/// (left by a crash) is ignored.
+pub fn read_trace(path: impl AsRef<Path>) -> Result<Trace, SnapshotError> {
+    let mut reader = BufReader::new(File::open(path).map_err(SnapshotError::Io)?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(read_error)?;
+    if magic != TRACE_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let mut version = [0u8; 4];
+    let mut sample_one_in = [0u8; 8];
+    let mut started_at = [0u8; 8];
+    reader.read_exact(&mut version).map_err(read_error)?;
+    let version = u32::from_le_bytes(version);
+    if version != TRACE_FORMAT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    reader.read_exact(&mut sample_one_in).map_err(read_error)?;
+    reader.read_exact(&mut started_at).map_err(read_error)?;
+
+    let mut records = Vec::new();
+    let mut record = [0u8; RECORD_LEN];
+    loop {
+        match reader.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(SnapshotError::Io(err)),
+        }
+        let Some(op) = TraceOp::from_u8(record[0]) else {
+            return Err(SnapshotError::Decode(DecodeError::UnexpectedVariant {
+                type_name: "TraceOp",
+                allowed: &AllowedEnumVariants::Range { min: 0, max: 3 },
+                found: record[0] as u32,
+            }));
+        };
+        records.push(TraceRecord {
+            op,
+            micros: u64::from_le_bytes(record[1..9].try_into().unwrap()),
+            key_hash: u64::from_le_bytes(record[9..17].try_into().unwrap()),
+            size: u32::from_le_bytes(record[17..21].try_into().unwrap()),
+        });
+    }
+    Ok(Trace {
+        sample_one_in: u64::from_le_bytes(sample_one_in),
+        started_at: u64::from_le_bytes(started_at),
+        records,
+    })
+}